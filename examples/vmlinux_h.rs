@@ -0,0 +1,11 @@
+use anyhow::Result;
+use rust_ebpf_loader::{btf_dump, btf_parser, btf_source::BtfSource};
+
+/// Prints a vmlinux.h for the running kernel, e.g.
+/// `cargo run --example vmlinux_h > vmlinux.h`
+fn main() -> Result<()> {
+    let kernel_btf = BtfSource::default_search_path()?.read()?;
+    let vmlinux_btf = btf_parser::parse_btf(&kernel_btf.vmlinux, 0)?;
+    print!("{}", btf_dump::dump_vmlinux_header(&vmlinux_btf)?);
+    Ok(())
+}
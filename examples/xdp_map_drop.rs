@@ -1,30 +1,23 @@
 use anyhow::{Context as _, Result};
 use rust_ebpf_loader::{
-    elf, elf_parser,
-    syscalls_wrapper::{self, BpfMapType, BpfMapUpdateFlag, BpfProgType},
+    object::Object,
+    syscalls_wrapper::{self, BpfMapType, BpfMapUpdateFlag},
 };
 
 fn main() -> Result<()> {
-    let path = "./ebpf_bin/xdp_map_drop.o";
-    let elf = elf_parser::parse_elf(path)?;
-    let mut xdp_section = elf
-        .get_section_body("xdp")
-        .context("Failed to get xdp section")?
-        .to_vec();
-    let xdp_rel_section = elf.parse_relocation_section(".relxdp");
+    let mut obj = Object::open("./ebpf_bin/xdp_map_drop.o")?;
+    // the object was built without -g, so its .maps section has no BTF to create drop_flag
+    // from and the map has to be supplied
     let map = unsafe { syscalls_wrapper::bpf_map_create(BpfMapType::Array, 4, 4, 1)? };
     unsafe { syscalls_wrapper::bpf_map_update_elem(map, &0, &1, BpfMapUpdateFlag::Any)? };
-    elf::relocate(
-        &mut xdp_section,
-        &xdp_rel_section.unwrap(),
-        &vec![(3, map as i64)].into_iter().collect(),
-    );
-    let mut log_buf = vec![0; 4096];
-    let prog_fd = unsafe {
-        syscalls_wrapper::bpf_prog_load(BpfProgType::Xdp, &xdp_section, "GPL", &mut log_buf, 1)?
-    };
+    obj.set_map_fd("drop_flag", map)?;
+    obj.load()?;
+    let prog_fd = obj
+        .program("xdp_prog_map")
+        .and_then(|prog| prog.fd())
+        .context("Failed to get xdp program")?;
     // attach xdp to lo interface
-    let ret = unsafe { syscalls_wrapper::xdp_attach(1, prog_fd as i32)? };
+    let ret = unsafe { syscalls_wrapper::xdp_attach(1, prog_fd)? };
     std::thread::sleep(std::time::Duration::from_secs(3));
     unsafe { syscalls_wrapper::bpf_map_update_elem(map, &0, &0, BpfMapUpdateFlag::Any)? };
     println!("map updated");
@@ -38,6 +31,7 @@ fn main() -> Result<()> {
     Ok(())
 }
 
+#[allow(dead_code)]
 static PROGRAM: &str = r#"
 #include <linux/bpf.h>
 #include <bpf/bpf_helpers.h>
@@ -1,3 +1,10 @@
+use std::{collections::HashMap, sync::OnceLock};
+
+use crate::{
+    common,
+    error::{Context as _, Error, Result},
+};
+
 #[repr(C)]
 #[derive(Debug, Clone)]
 pub struct BtfHeader {
@@ -36,9 +43,9 @@ pub enum BtfKind {
 }
 
 impl TryFrom<u32> for BtfKind {
-    type Error = anyhow::Error;
+    type Error = Error;
 
-    fn try_from(value: u32) -> Result<Self, Self::Error> {
+    fn try_from(value: u32) -> Result<Self> {
         match value {
             1 => Ok(BtfKind::Int),
             2 => Ok(BtfKind::Ptr),
@@ -59,7 +66,7 @@ impl TryFrom<u32> for BtfKind {
             17 => Ok(BtfKind::DeclTag),
             18 => Ok(BtfKind::TypeTag),
             19 => Ok(BtfKind::Enum64),
-            _ => Err(anyhow::anyhow!("Invalid BTF kind: {}", value)),
+            _ => Err(Error::Parse(format!("Invalid BTF kind: {}", value))),
         }
     }
 }
@@ -74,11 +81,216 @@ pub struct BtfType {
     pub detail: BtfTypeDetail,
 }
 
+impl BtfType {
+    /// Whether an enum's values are signed, which BTF records in `kind_flag`.
+    pub fn is_signed_enum(&self) -> bool {
+        matches!(self.kind, BtfKind::Enum | BtfKind::Enum64) && self.kind_flag
+    }
+
+    /// Number of bytes the type occupies in the raw type section, including the data that
+    /// follows the common `struct btf_type` header for kinds that have any.
+    pub fn encoded_len(&self) -> usize {
+        let vlen = self.vlen as usize;
+        let extra = match self.kind {
+            BtfKind::Int | BtfKind::Var | BtfKind::DeclTag => 4,
+            BtfKind::Array => size_of::<BtfArray>(),
+            BtfKind::Struct | BtfKind::Union => vlen * size_of::<BtfMember>(),
+            BtfKind::Enum => vlen * size_of::<BtfEnum>(),
+            BtfKind::Enum64 => vlen * size_of::<BtfEnum64>(),
+            BtfKind::FuncProto => vlen * size_of::<BtfParam>(),
+            BtfKind::DataSec => vlen * size_of::<BtfVarSecinfo>(),
+            _ => 0,
+        };
+        12 + extra
+    }
+
+    /// Every field holding a type id, including `size_or_type` for the kinds that use it as
+    /// one and the index type of arrays.
+    pub fn type_ids_mut(&mut self) -> Vec<&mut u32> {
+        let mut type_ids = Vec::new();
+        if matches!(
+            self.kind,
+            BtfKind::Ptr
+                | BtfKind::Typedef
+                | BtfKind::Volatile
+                | BtfKind::Const
+                | BtfKind::Restrict
+                | BtfKind::Func
+                | BtfKind::FuncProto
+                | BtfKind::Var
+                | BtfKind::DeclTag
+                | BtfKind::TypeTag
+        ) {
+            type_ids.push(&mut self.size_or_type);
+        }
+        match &mut self.detail {
+            BtfTypeDetail::Struct(members) => {
+                type_ids.extend(members.iter_mut().map(|member| &mut member.type_id))
+            }
+            BtfTypeDetail::Array(array) => {
+                type_ids.push(&mut array.type_id);
+                type_ids.push(&mut array.index_type);
+            }
+            BtfTypeDetail::FuncProto(params) => {
+                type_ids.extend(params.iter_mut().map(|param| &mut param.type_id))
+            }
+            BtfTypeDetail::DataSec(vars) => {
+                type_ids.extend(vars.iter_mut().map(|var| &mut var.type_id))
+            }
+            _ => {}
+        }
+        type_ids
+    }
+
+    /// The type ids in [`BtfType::type_ids_mut`], in the same order.
+    pub fn type_ids(&self) -> Vec<u32> {
+        self.clone()
+            .type_ids_mut()
+            .into_iter()
+            .map(|id| *id)
+            .collect()
+    }
+
+    /// Every field holding a string offset: the type name and those of members, enum values
+    /// and parameters.
+    pub fn name_offs_mut(&mut self) -> Vec<&mut u32> {
+        let mut name_offs = vec![&mut self.name_off];
+        match &mut self.detail {
+            BtfTypeDetail::Struct(members) => {
+                name_offs.extend(members.iter_mut().map(|member| &mut member.name_off))
+            }
+            BtfTypeDetail::Enum(values) => {
+                name_offs.extend(values.iter_mut().map(|value| &mut value.name_off))
+            }
+            BtfTypeDetail::Enum64(values) => {
+                name_offs.extend(values.iter_mut().map(|value| &mut value.name_off))
+            }
+            BtfTypeDetail::FuncProto(params) => {
+                name_offs.extend(params.iter_mut().map(|param| &mut param.name_off))
+            }
+            _ => {}
+        }
+        name_offs
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum BtfTypeDetail {
     None,
-    Ignored,
+    Int(BtfInt),
     Struct(Vec<BtfMember>),
+    Array(BtfArray),
+    Enum(Vec<BtfEnum>),
+    Enum64(Vec<BtfEnum64>),
+    Func(BtfLinkage),
+    FuncProto(Vec<BtfParam>),
+    Var(BtfVar),
+    DataSec(Vec<BtfVarSecinfo>),
+    DeclTag(BtfDeclTag),
+}
+
+/// Linkage of a `BTF_KIND_FUNC` (stored in `vlen`) or a `BTF_KIND_VAR`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BtfLinkage {
+    Static = 0,
+    Global = 1,
+    Extern = 2,
+}
+
+impl TryFrom<u32> for BtfLinkage {
+    type Error = Error;
+
+    fn try_from(value: u32) -> Result<Self> {
+        match value {
+            0 => Ok(BtfLinkage::Static),
+            1 => Ok(BtfLinkage::Global),
+            2 => Ok(BtfLinkage::Extern),
+            _ => Err(Error::Parse(format!("Invalid BTF linkage: {}", value))),
+        }
+    }
+}
+
+/// Bits of [`BtfInt::encoding`].
+pub const BTF_INT_SIGNED: u8 = 1 << 0;
+pub const BTF_INT_CHAR: u8 = 1 << 1;
+pub const BTF_INT_BOOL: u8 = 1 << 2;
+
+/// Longest typedef/modifier chain [`Btf::resolve`] follows, as in libbpf.
+const MAX_RESOLVE_DEPTH: u32 = 32;
+
+/// The `u32` following a `BTF_KIND_INT` type, split into its fields.
+#[derive(Debug, Clone)]
+pub struct BtfInt {
+    pub encoding: u8,
+    pub offset: u8,
+    pub bits: u8,
+}
+
+impl From<u32> for BtfInt {
+    fn from(value: u32) -> Self {
+        BtfInt {
+            encoding: ((value >> 24) & 0x0f) as u8,
+            offset: ((value >> 16) & 0xff) as u8,
+            bits: (value & 0xff) as u8,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct BtfArray {
+    pub type_id: u32,
+    pub index_type: u32,
+    pub nelems: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct BtfEnum {
+    pub name_off: u32,
+    pub val: i32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct BtfEnum64 {
+    pub name_off: u32,
+    pub val_lo32: u32,
+    pub val_hi32: u32,
+}
+
+impl BtfEnum64 {
+    pub fn get_value(&self) -> u64 {
+        ((self.val_hi32 as u64) << 32) | self.val_lo32 as u64
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct BtfParam {
+    pub name_off: u32,
+    pub type_id: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct BtfVar {
+    pub linkage: BtfLinkage,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct BtfVarSecinfo {
+    pub type_id: u32,
+    pub offset: u32,
+    pub size: u32,
+}
+
+/// -1 when the tag applies to the type or variable itself, otherwise the index of the member or
+/// function parameter it applies to.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct BtfDeclTag {
+    pub component_idx: i32,
 }
 
 #[repr(C)]
@@ -97,6 +309,15 @@ impl BtfMember {
             self.offset
         }
     }
+
+    /// Returns the bitfield size, or 0 if the member is not a bitfield.
+    pub fn get_bitfield_size(&self, kind_flag: bool) -> u32 {
+        if kind_flag {
+            self.offset >> 24
+        } else {
+            0
+        }
+    }
 }
 
 #[repr(C)]
@@ -133,9 +354,9 @@ pub enum BpfCoreReloKind {
 }
 
 impl TryFrom<u32> for BpfCoreReloKind {
-    type Error = anyhow::Error;
+    type Error = Error;
 
-    fn try_from(value: u32) -> Result<Self, Self::Error> {
+    fn try_from(value: u32) -> Result<Self> {
         match value {
             0 => Ok(BpfCoreReloKind::FieldByteOffset),
             1 => Ok(BpfCoreReloKind::FieldByteSize),
@@ -150,10 +371,10 @@ impl TryFrom<u32> for BpfCoreReloKind {
             10 => Ok(BpfCoreReloKind::EnumValExists),
             11 => Ok(BpfCoreReloKind::EnumValValue),
             12 => Ok(BpfCoreReloKind::TypeMatches),
-            _ => Err(anyhow::anyhow!(
+            _ => Err(Error::Parse(format!(
                 "Invalid BPF core relocation kind: {}",
                 value
-            )),
+            ))),
         }
     }
 }
@@ -167,6 +388,35 @@ pub struct BpfCoreRelo {
     pub kind: BpfCoreReloKind,
 }
 
+/// A `.BTF.ext` func_info record, laid out like the kernel's `struct bpf_func_info`. In the ELF
+/// file `insn_off` is a byte offset into the section; the kernel expects an instruction index.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct BpfFuncInfo {
+    pub insn_off: u32,
+    pub type_id: u32,
+}
+
+/// A `.BTF.ext` line_info record, laid out like the kernel's `struct bpf_line_info`.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct BpfLineInfo {
+    pub insn_off: u32,
+    pub file_name_off: u32,
+    pub line_off: u32,
+    pub line_col: u32,
+}
+
+impl BpfLineInfo {
+    pub fn line(&self) -> u32 {
+        self.line_col >> 10
+    }
+
+    pub fn column(&self) -> u32 {
+        self.line_col & 0x3ff
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct BtfExtInfoSec<T> {
     pub sec_name_off: u32,
@@ -178,10 +428,238 @@ pub struct Btf<'a> {
     pub header: &'a BtfHeader,
     pub string_section: &'a [u8],
     pub type_section: Vec<BtfType>,
+    /// Set for split BTF (kernel modules): type ids and string offsets continue where the base
+    /// BTF's end, and `type_section` only holds the types added on top of it.
+    pub base: Option<&'a Btf<'a>>,
+    /// Ids of the named types in `type_section`, built on the first lookup by name.
+    pub(crate) name_index: OnceLock<HashMap<Box<str>, Vec<u32>>>,
 }
 
+impl<'a> Btf<'a> {
+    /// Id of the first entry of `type_section`.
+    pub fn start_id(&self) -> u32 {
+        self.base.map_or(0, |base| base.type_count())
+    }
+
+    /// Number of type ids in use, including void and the base BTF's types.
+    pub fn type_count(&self) -> u32 {
+        self.start_id() + self.type_section.len() as u32
+    }
+
+    fn string_len(&self) -> usize {
+        self.base.map_or(0, |base| base.string_len()) + self.string_section.len()
+    }
+
+    pub fn type_by_id(&self, type_id: u32) -> Option<&BtfType> {
+        match self.base {
+            Some(base) if type_id < self.start_id() => base.type_by_id(type_id),
+            _ => self
+                .type_section
+                .get(type_id.checked_sub(self.start_id())? as usize),
+        }
+    }
+
+    /// Like [`Btf::type_by_id`], but fails on ids that are out of range.
+    pub fn get_type(&self, type_id: u32) -> Result<&BtfType> {
+        self.type_by_id(type_id)
+            .with_context(|| format!("Invalid BTF type id: {type_id}"))
+    }
+
+    /// Iterates over every type id and its type, starting with void and including the types of
+    /// the base BTF.
+    pub fn types(&self) -> impl Iterator<Item = (u32, &BtfType)> {
+        (0..self.type_count()).filter_map(|type_id| Some((type_id, self.type_by_id(type_id)?)))
+    }
+
+    pub fn name_of(&self, btf_type: &BtfType) -> Result<&'a str> {
+        self.name_by_offset(btf_type.name_off)
+    }
+
+    fn name_index(&self) -> &HashMap<Box<str>, Vec<u32>> {
+        self.name_index.get_or_init(|| {
+            let mut name_index: HashMap<Box<str>, Vec<u32>> = HashMap::new();
+            for (type_id, btf_type) in (self.start_id()..).zip(&self.type_section) {
+                if let Ok(name) = self.name_of(btf_type)
+                    && !name.is_empty()
+                {
+                    name_index.entry(name.into()).or_default().push(type_id);
+                }
+            }
+            name_index
+        })
+    }
+
+    /// Ids of all types named `name`, base BTF types first.
+    pub fn find_by_name(&self, name: &str) -> Vec<u32> {
+        let mut type_ids = self
+            .base
+            .map_or_else(Vec::new, |base| base.find_by_name(name));
+        type_ids.extend(self.name_index().get(name).into_iter().flatten());
+        type_ids
+    }
+
+    /// Returns the first type named `name` of the given kind.
+    pub fn find_by_name_kind(&self, name: &str, kind: BtfKind) -> Option<(u32, &BtfType)> {
+        if let Some(found) = self
+            .base
+            .and_then(|base| base.find_by_name_kind(name, kind))
+        {
+            return Some(found);
+        }
+        self.name_index()
+            .get(name)?
+            .iter()
+            .filter_map(|&type_id| Some((type_id, self.type_by_id(type_id)?)))
+            .find(|(_, btf_type)| btf_type.kind == kind)
+    }
+
+    /// Follows typedefs, type tags and const/volatile/restrict modifiers to the underlying type.
+    pub fn resolve(&self, mut type_id: u32) -> Result<&BtfType> {
+        let start_id = type_id;
+        for _ in 0..MAX_RESOLVE_DEPTH {
+            let btf_type = self.get_type(type_id)?;
+            match btf_type.kind {
+                BtfKind::Typedef
+                | BtfKind::Volatile
+                | BtfKind::Const
+                | BtfKind::Restrict
+                | BtfKind::TypeTag => type_id = btf_type.size_or_type,
+                _ => return Ok(btf_type),
+            }
+        }
+        Err(Error::Parse(format!(
+            "Modifier chain of BTF type {start_id} is too long or cyclic"
+        )))
+    }
+
+    /// Size in bytes of a value of the given type.
+    pub fn size_of(&self, type_id: u32) -> Result<u32> {
+        let btf_type = self.resolve(type_id)?;
+        match (&btf_type.kind, &btf_type.detail) {
+            (BtfKind::Ptr, _) => Ok(8),
+            (BtfKind::Array, BtfTypeDetail::Array(array)) => self
+                .size_of(array.type_id)?
+                .checked_mul(array.nelems)
+                .with_context(|| format!("Size of BTF array {type_id} out of range")),
+            (BtfKind::Var, _) => self.size_of(btf_type.size_or_type),
+            (
+                BtfKind::Int
+                | BtfKind::Struct
+                | BtfKind::Union
+                | BtfKind::Enum
+                | BtfKind::Enum64
+                | BtfKind::Float
+                | BtfKind::DataSec,
+                _,
+            ) => Ok(btf_type.size_or_type),
+            (kind, _) => Err(Error::Parse(format!(
+                "Cannot determine the size of BTF kind {kind:?}"
+            ))),
+        }
+    }
+
+    /// Natural alignment in bytes of a value of the given type. Structs and unions whose layout
+    /// is not naturally aligned are packed and have an alignment of 1.
+    pub fn align_of(&self, type_id: u32) -> Result<u32> {
+        let btf_type = self.resolve(type_id)?;
+        match (&btf_type.kind, &btf_type.detail) {
+            (BtfKind::Ptr, _) => Ok(8),
+            (BtfKind::Int | BtfKind::Enum | BtfKind::Enum64 | BtfKind::Float, _) => {
+                Ok(btf_type.size_or_type.clamp(1, 8))
+            }
+            (BtfKind::Array, BtfTypeDetail::Array(array)) => self.align_of(array.type_id),
+            (BtfKind::Struct | BtfKind::Union, _) => match self.struct_alignment(btf_type)? {
+                (true, _) => Ok(1),
+                (false, max_align) => Ok(max_align),
+            },
+            (kind, _) => Err(Error::Parse(format!(
+                "Cannot determine the alignment of BTF kind {kind:?}"
+            ))),
+        }
+    }
+
+    /// Whether a struct or union needs `__attribute__((packed))`: some member is not naturally
+    /// aligned, or the size is not a multiple of the largest member alignment.
+    pub fn is_packed(&self, btf_type: &BtfType) -> Result<bool> {
+        Ok(self.struct_alignment(btf_type)?.0)
+    }
+
+    /// Returns whether a struct or union is packed and the largest alignment of its members.
+    fn struct_alignment(&self, btf_type: &BtfType) -> Result<(bool, u32)> {
+        let BtfTypeDetail::Struct(members) = &btf_type.detail else {
+            return Ok((false, 1));
+        };
+        let mut packed = false;
+        let mut max_align = 1;
+        for member in members {
+            let align = self.align_of(member.type_id)?;
+            if member.get_bitfield_size(btf_type.kind_flag) == 0
+                && member.get_offset(btf_type.kind_flag) % (8 * align) != 0
+            {
+                packed = true;
+            }
+            max_align = max_align.max(align);
+        }
+        packed |= !btf_type.size_or_type.is_multiple_of(max_align);
+        Ok((packed, max_align))
+    }
+
+    /// Looks up a direct member of a struct or union by name. Members of anonymous nested
+    /// structs and unions are not searched.
+    pub fn member_by_name<'t>(&self, btf_type: &'t BtfType, name: &str) -> Option<&'t BtfMember> {
+        let BtfTypeDetail::Struct(members) = &btf_type.detail else {
+            return None;
+        };
+        members.iter().find(|member| {
+            self.name_by_offset(member.name_off)
+                .is_ok_and(|n| n == name)
+        })
+    }
+
+    pub fn name_by_offset(&self, offset: u32) -> Result<&'a str> {
+        let offset = offset as usize;
+        match self.base {
+            Some(base) if offset < base.string_len() => base.name_by_offset(offset as u32),
+            Some(base) => common::get_name_from_string_section(
+                self.string_section,
+                offset - base.string_len(),
+            ),
+            None => common::get_name_from_string_section(self.string_section, offset),
+        }
+    }
+}
+
+/// Parsed `.BTF.ext`. Record offsets are byte offsets into the ELF section named by each
+/// [`BtfExtInfoSec`], and names resolve against the object's `.BTF`.
 #[derive(Debug, Clone)]
-pub struct BtfExt<'a> {
-    pub header: &'a BtfExtHeader,
+pub struct BtfExt {
+    pub header: BtfExtHeader,
+    pub func_info_part: Vec<BtfExtInfoSec<BpfFuncInfo>>,
+    pub line_info_part: Vec<BtfExtInfoSec<BpfLineInfo>>,
     pub core_relo_part: Vec<BtfExtInfoSec<BpfCoreRelo>>,
 }
+
+impl BtfExt {
+    /// Func info records of the ELF section `section_name`.
+    pub fn func_info(&self, btf: &Btf, section_name: &str) -> Result<&[BpfFuncInfo]> {
+        section_records(&self.func_info_part, btf, section_name)
+    }
+
+    /// Line info records of the ELF section `section_name`.
+    pub fn line_info(&self, btf: &Btf, section_name: &str) -> Result<&[BpfLineInfo]> {
+        section_records(&self.line_info_part, btf, section_name)
+    }
+}
+
+fn section_records<'s, T>(
+    part: &'s [BtfExtInfoSec<T>],
+    btf: &Btf,
+    section_name: &str,
+) -> Result<&'s [T]> {
+    for info_sec in part {
+        if btf.name_by_offset(info_sec.sec_name_off)? == section_name {
+            return Ok(&info_sec.data);
+        }
+    }
+    Ok(&[])
+}
@@ -0,0 +1,498 @@
+use std::collections::HashMap;
+
+use crate::{
+    btf::{
+        Btf, BtfArray, BtfDeclTag, BtfEnum, BtfEnum64, BtfInt, BtfKind, BtfLinkage, BtfMember,
+        BtfParam, BtfType, BtfTypeDetail, BtfVar, BtfVarSecinfo,
+    },
+    common,
+    error::{Error, Result},
+};
+
+const BTF_MAGIC: u16 = 0xeb9f;
+const BTF_VERSION: u8 = 1;
+const BTF_HEADER_LEN: u32 = 24;
+
+/// Encodes a raw BTF blob with a default header from the contents of the type and string
+/// sections.
+pub(crate) fn encode_sections(types: &[u8], strings: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(BTF_HEADER_LEN as usize + types.len() + strings.len());
+    data.extend_from_slice(&BTF_MAGIC.to_le_bytes());
+    data.extend_from_slice(&[BTF_VERSION, 0]);
+    let (type_len, str_len) = (types.len() as u32, strings.len() as u32);
+    for field in [BTF_HEADER_LEN, 0, type_len, type_len, str_len] {
+        data.extend_from_slice(&field.to_le_bytes());
+    }
+    data.extend_from_slice(types);
+    data.extend_from_slice(strings);
+    data
+}
+
+/// An owned, mutable BTF. Unlike [`Btf`], which borrows the data it was parsed from, types can
+/// be added and changed, and the result encoded with [`BtfBuilder::encode`] for
+/// `BPF_BTF_LOAD` or for parsing again with [`crate::btf_parser::parse_btf`].
+///
+/// Type ids work as in [`Btf`]: id 0 is void and types are numbered in the order they are
+/// added. Strings are deduplicated, so adding a name twice yields the same offset.
+#[derive(Debug, Clone)]
+pub struct BtfBuilder {
+    types: Vec<BtfType>,
+    strings: Vec<u8>,
+    string_offsets: HashMap<String, u32>,
+}
+
+impl Default for BtfBuilder {
+    fn default() -> Self {
+        BtfBuilder::new()
+    }
+}
+
+impl BtfBuilder {
+    pub fn new() -> BtfBuilder {
+        let void = BtfType {
+            name_off: 0,
+            vlen: 0,
+            kind: BtfKind::Int,
+            kind_flag: false,
+            size_or_type: 0,
+            detail: BtfTypeDetail::None,
+        };
+        BtfBuilder {
+            types: vec![void],
+            strings: vec![0],
+            string_offsets: HashMap::from([(String::new(), 0)]),
+        }
+    }
+
+    /// Copies the types and strings of `btf`, keeping type ids and string offsets. Split BTF
+    /// is not supported, as its ids depend on the base.
+    pub fn from_btf(btf: &Btf) -> Result<BtfBuilder> {
+        if btf.base.is_some() {
+            return Err(Error::InvalidArgument(
+                "Cannot build on split BTF".to_string(),
+            ));
+        }
+        let mut string_offsets = HashMap::new();
+        let mut offset = 0;
+        for string in btf.string_section.split(|&c| c == 0) {
+            if offset < btf.string_section.len() {
+                string_offsets
+                    .entry(std::str::from_utf8(string)?.to_string())
+                    .or_insert(offset as u32);
+            }
+            offset += string.len() + 1;
+        }
+        let mut strings = btf.string_section.to_vec();
+        if strings.last() != Some(&0) {
+            strings.push(0);
+        }
+        Ok(BtfBuilder {
+            types: btf.type_section.clone(),
+            strings,
+            string_offsets,
+        })
+    }
+
+    /// Adds `string` to the string section unless it is already there, and returns its offset.
+    pub fn add_string(&mut self, string: &str) -> u32 {
+        if let Some(&offset) = self.string_offsets.get(string) {
+            return offset;
+        }
+        let offset = self.strings.len() as u32;
+        self.strings.extend_from_slice(string.as_bytes());
+        self.strings.push(0);
+        self.string_offsets.insert(string.to_string(), offset);
+        offset
+    }
+
+    pub fn name_by_offset(&self, offset: u32) -> Result<&str> {
+        common::get_name_from_string_section(&self.strings, offset as usize)
+    }
+
+    pub fn name_of(&self, btf_type: &BtfType) -> Result<&str> {
+        self.name_by_offset(btf_type.name_off)
+    }
+
+    /// Number of type ids in use, including void.
+    pub fn type_count(&self) -> u32 {
+        self.types.len() as u32
+    }
+
+    pub fn type_by_id(&self, type_id: u32) -> Option<&BtfType> {
+        self.types.get(type_id as usize)
+    }
+
+    /// Gives mutable access to a type, e.g. to point it at a type added later. Changes to
+    /// `detail` must keep `vlen` in sync; [`BtfBuilder::encode`] checks that they do.
+    pub fn type_by_id_mut(&mut self, type_id: u32) -> Option<&mut BtfType> {
+        self.types
+            .get_mut(type_id as usize)
+            .filter(|_| type_id != 0)
+    }
+
+    /// Iterates over `(type_id, type)` in id order, starting with void.
+    pub fn types(&self) -> impl Iterator<Item = (u32, &BtfType)> {
+        self.types
+            .iter()
+            .enumerate()
+            .map(|(id, btf_type)| (id as u32, btf_type))
+    }
+
+    /// Adds a type and returns its id. `vlen` is derived from `detail`, and `detail` must be
+    /// the one the parser produces for `kind`.
+    pub fn add_type(
+        &mut self,
+        name: &str,
+        kind: BtfKind,
+        kind_flag: bool,
+        size_or_type: u32,
+        detail: BtfTypeDetail,
+    ) -> Result<u32> {
+        let vlen = detail_vlen(kind, &detail)?;
+        let name_off = self.add_string(name);
+        self.types.push(BtfType {
+            name_off,
+            vlen,
+            kind,
+            kind_flag,
+            size_or_type,
+            detail,
+        });
+        Ok(self.types.len() as u32 - 1)
+    }
+
+    /// Adds a type whose name offsets already point into this builder's strings, e.g. ones
+    /// returned by [`BtfBuilder::add_string`], and returns its id.
+    pub fn push_type(&mut self, mut btf_type: BtfType) -> Result<u32> {
+        if btf_type.vlen != detail_vlen(btf_type.kind, &btf_type.detail)? {
+            return Err(Error::InvalidArgument(
+                "vlen of BTF type does not match its detail".to_string(),
+            ));
+        }
+        if let Some(name_off) = btf_type
+            .name_offs_mut()
+            .into_iter()
+            .find(|name_off| **name_off as usize >= self.strings.len())
+        {
+            return Err(Error::InvalidArgument(format!(
+                "String offset {name_off} out of bounds"
+            )));
+        }
+        self.types.push(btf_type);
+        Ok(self.types.len() as u32 - 1)
+    }
+
+    /// Adds an integer of `size` bytes; `encoding` is a combination of the `BTF_INT_*` bits.
+    pub fn add_int(&mut self, name: &str, size: u32, encoding: u8) -> Result<u32> {
+        let int = BtfInt {
+            encoding,
+            offset: 0,
+            bits: (size * 8) as u8,
+        };
+        self.add_type(name, BtfKind::Int, false, size, BtfTypeDetail::Int(int))
+    }
+
+    /// Adds a type that only refers to another one: a pointer, typedef, `const`, `volatile`,
+    /// `restrict` or type tag.
+    pub fn add_ref(&mut self, name: &str, kind: BtfKind, type_id: u32) -> Result<u32> {
+        self.add_type(name, kind, false, type_id, BtfTypeDetail::None)
+    }
+
+    pub fn add_array(&mut self, type_id: u32, index_type: u32, nelems: u32) -> Result<u32> {
+        let array = BtfArray {
+            type_id,
+            index_type,
+            nelems,
+        };
+        self.add_type("", BtfKind::Array, false, 0, BtfTypeDetail::Array(array))
+    }
+
+    /// Adds a struct, or a union with `kind` [`BtfKind::Union`], from `(name, type_id,
+    /// bit_offset)` members. Bitfields need [`BtfBuilder::add_type`] with `kind_flag` set.
+    pub fn add_struct(
+        &mut self,
+        name: &str,
+        kind: BtfKind,
+        size: u32,
+        members: &[(&str, u32, u32)],
+    ) -> Result<u32> {
+        let members = members
+            .iter()
+            .map(|&(name, type_id, offset)| BtfMember {
+                name_off: self.add_string(name),
+                type_id,
+                offset,
+            })
+            .collect();
+        self.add_type(name, kind, false, size, BtfTypeDetail::Struct(members))
+    }
+
+    pub fn add_enum(&mut self, name: &str, size: u32, values: &[(&str, i32)]) -> Result<u32> {
+        let values = values
+            .iter()
+            .map(|&(name, val)| BtfEnum {
+                name_off: self.add_string(name),
+                val,
+            })
+            .collect();
+        self.add_type(
+            name,
+            BtfKind::Enum,
+            false,
+            size,
+            BtfTypeDetail::Enum(values),
+        )
+    }
+
+    pub fn add_func_proto(&mut self, ret_type: u32, params: &[(&str, u32)]) -> Result<u32> {
+        let params = params
+            .iter()
+            .map(|&(name, type_id)| BtfParam {
+                name_off: self.add_string(name),
+                type_id,
+            })
+            .collect();
+        let detail = BtfTypeDetail::FuncProto(params);
+        self.add_type("", BtfKind::FuncProto, false, ret_type, detail)
+    }
+
+    pub fn add_func(&mut self, name: &str, proto: u32, linkage: BtfLinkage) -> Result<u32> {
+        let detail = BtfTypeDetail::Func(linkage);
+        self.add_type(name, BtfKind::Func, false, proto, detail)
+    }
+
+    pub fn add_var(&mut self, name: &str, type_id: u32, linkage: BtfLinkage) -> Result<u32> {
+        let detail = BtfTypeDetail::Var(BtfVar { linkage });
+        self.add_type(name, BtfKind::Var, false, type_id, detail)
+    }
+
+    pub fn add_datasec(&mut self, name: &str, size: u32, vars: Vec<BtfVarSecinfo>) -> Result<u32> {
+        let detail = BtfTypeDetail::DataSec(vars);
+        self.add_type(name, BtfKind::DataSec, false, size, detail)
+    }
+
+    /// Encodes the header, type section and string section.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut types = Vec::new();
+        for (type_id, btf_type) in self.types().skip(1) {
+            if btf_type.vlen != detail_vlen(btf_type.kind, &btf_type.detail)? {
+                return Err(Error::InvalidArgument(format!(
+                    "vlen of BTF type {type_id} does not match its detail"
+                )));
+            }
+            encode_type(&mut types, btf_type);
+        }
+        Ok(encode_sections(&types, &self.strings))
+    }
+}
+
+/// The `vlen` that goes with `detail`, which must match `kind`.
+fn detail_vlen(kind: BtfKind, detail: &BtfTypeDetail) -> Result<u16> {
+    let vlen = match (kind, detail) {
+        (BtfKind::Int, BtfTypeDetail::Int(_))
+        | (BtfKind::Array, BtfTypeDetail::Array(_))
+        | (BtfKind::Var, BtfTypeDetail::Var(_))
+        | (BtfKind::DeclTag, BtfTypeDetail::DeclTag(_))
+        | (
+            BtfKind::Ptr
+            | BtfKind::Fwd
+            | BtfKind::Typedef
+            | BtfKind::Volatile
+            | BtfKind::Const
+            | BtfKind::Restrict
+            | BtfKind::Float
+            | BtfKind::TypeTag,
+            BtfTypeDetail::None,
+        ) => 0,
+        (BtfKind::Struct | BtfKind::Union, BtfTypeDetail::Struct(members)) => members.len(),
+        (BtfKind::Enum, BtfTypeDetail::Enum(values)) => values.len(),
+        (BtfKind::Enum64, BtfTypeDetail::Enum64(values)) => values.len(),
+        (BtfKind::Func, BtfTypeDetail::Func(linkage)) => *linkage as usize,
+        (BtfKind::FuncProto, BtfTypeDetail::FuncProto(params)) => params.len(),
+        (BtfKind::DataSec, BtfTypeDetail::DataSec(vars)) => vars.len(),
+        (kind, detail) => {
+            return Err(Error::InvalidArgument(format!(
+                "BTF kind {kind:?} cannot have detail {detail:?}"
+            )));
+        }
+    };
+    u16::try_from(vlen)
+        .map_err(|_| Error::InvalidArgument(format!("Too many entries for BTF kind {kind:?}")))
+}
+
+pub(crate) fn encode_type(out: &mut Vec<u8>, btf_type: &BtfType) {
+    let mut push = |word: u32| out.extend_from_slice(&word.to_le_bytes());
+    let info =
+        ((btf_type.kind_flag as u32) << 31) | ((btf_type.kind as u32) << 24) | btf_type.vlen as u32;
+    push(btf_type.name_off);
+    push(info);
+    push(btf_type.size_or_type);
+    match &btf_type.detail {
+        BtfTypeDetail::None | BtfTypeDetail::Func(_) => {}
+        BtfTypeDetail::Int(int) => {
+            push(((int.encoding as u32) << 24) | ((int.offset as u32) << 16) | int.bits as u32)
+        }
+        BtfTypeDetail::Struct(members) => {
+            for BtfMember {
+                name_off,
+                type_id,
+                offset,
+            } in members
+            {
+                push(*name_off);
+                push(*type_id);
+                push(*offset);
+            }
+        }
+        BtfTypeDetail::Array(array) => {
+            push(array.type_id);
+            push(array.index_type);
+            push(array.nelems);
+        }
+        BtfTypeDetail::Enum(values) => {
+            for value in values {
+                push(value.name_off);
+                push(value.val as u32);
+            }
+        }
+        BtfTypeDetail::Enum64(values) => {
+            for BtfEnum64 {
+                name_off,
+                val_lo32,
+                val_hi32,
+            } in values
+            {
+                push(*name_off);
+                push(*val_lo32);
+                push(*val_hi32);
+            }
+        }
+        BtfTypeDetail::FuncProto(params) => {
+            for param in params {
+                push(param.name_off);
+                push(param.type_id);
+            }
+        }
+        BtfTypeDetail::Var(var) => push(var.linkage as u32),
+        BtfTypeDetail::DataSec(vars) => {
+            for var in vars {
+                push(var.type_id);
+                push(var.offset);
+                push(var.size);
+            }
+        }
+        BtfTypeDetail::DeclTag(BtfDeclTag { component_idx }) => push(*component_idx as u32),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{btf::BTF_INT_SIGNED, btf_parser};
+
+    /// A type of every shape the encoder writes, returning the builder and the struct's id.
+    fn sample() -> (BtfBuilder, u32) {
+        let mut builder = BtfBuilder::new();
+        let int = builder.add_int("int", 4, BTF_INT_SIGNED).unwrap();
+        let ptr = builder.add_ref("", BtfKind::Ptr, int).unwrap();
+        let array = builder.add_array(int, int, 4).unwrap();
+        let state = builder
+            .add_enum("state", 4, &[("IDLE", 0), ("BUSY", -1)])
+            .unwrap();
+        let point = builder
+            .add_struct(
+                "point",
+                BtfKind::Struct,
+                32,
+                &[("x", int, 0), ("next", ptr, 64), ("ys", array, 128)],
+            )
+            .unwrap();
+        let proto = builder.add_func_proto(int, &[("p", ptr)]).unwrap();
+        builder
+            .add_func("get_x", proto, BtfLinkage::Global)
+            .unwrap();
+        let var = builder
+            .add_var("origin", point, BtfLinkage::Global)
+            .unwrap();
+        let secinfo = BtfVarSecinfo {
+            type_id: var,
+            offset: 0,
+            size: 32,
+        };
+        builder.add_datasec(".data", 32, vec![secinfo]).unwrap();
+        builder.add_ref("state_t", BtfKind::Typedef, state).unwrap();
+        (builder, point)
+    }
+
+    #[test]
+    fn encoded_types_parse_back() {
+        let (builder, point) = sample();
+        let raw = builder.encode().unwrap();
+        let btf = btf_parser::parse_btf(&raw, 0).unwrap();
+
+        assert_eq!(btf.type_count(), builder.type_count());
+        for (type_id, btf_type) in builder.types().skip(1) {
+            let parsed = btf.get_type(type_id).unwrap();
+            assert_eq!(parsed.kind, btf_type.kind);
+            assert_eq!(parsed.vlen, btf_type.vlen);
+            assert_eq!(parsed.size_or_type, btf_type.size_or_type);
+            assert_eq!(
+                btf.name_of(parsed).unwrap(),
+                builder.name_of(btf_type).unwrap()
+            );
+        }
+        let BtfTypeDetail::Struct(members) = &btf.get_type(point).unwrap().detail else {
+            panic!("point is not a struct");
+        };
+        let members = members
+            .iter()
+            .map(|member| (btf.name_by_offset(member.name_off).unwrap(), member.offset))
+            .collect::<Vec<_>>();
+        assert_eq!(members, [("x", 0), ("next", 64), ("ys", 128)]);
+        let (state, _) = btf.find_by_name_kind("state", BtfKind::Enum).unwrap();
+        let BtfTypeDetail::Enum(values) = &btf.get_type(state).unwrap().detail else {
+            panic!("state is not an enum");
+        };
+        assert_eq!(values[1].val, -1);
+        assert_eq!(btf.size_of(point).unwrap(), 32);
+    }
+
+    #[test]
+    fn from_btf_reencodes_identically() {
+        let raw = sample().0.encode().unwrap();
+        let btf = btf_parser::parse_btf(&raw, 0).unwrap();
+        assert_eq!(BtfBuilder::from_btf(&btf).unwrap().encode().unwrap(), raw);
+    }
+
+    #[test]
+    fn deduplicates_strings() {
+        let mut builder = BtfBuilder::new();
+        assert_eq!(builder.add_string(""), 0);
+        let name = builder.add_string("task_struct");
+        assert_eq!(builder.add_string("task_struct"), name);
+        assert_ne!(builder.add_string("task"), name);
+        assert_eq!(builder.name_by_offset(name).unwrap(), "task_struct");
+    }
+
+    #[test]
+    fn rejects_inconsistent_types() {
+        let mut builder = BtfBuilder::new();
+        assert!(
+            builder
+                .add_type("x", BtfKind::Struct, false, 4, BtfTypeDetail::None)
+                .is_err()
+        );
+
+        let int = builder.add_int("int", 4, 0).unwrap();
+        let mut btf_type = builder.type_by_id(int).unwrap().clone();
+        btf_type.name_off = 1000;
+        assert!(builder.push_type(btf_type).is_err());
+
+        let point = builder
+            .add_struct("point", BtfKind::Struct, 4, &[("x", int, 0)])
+            .unwrap();
+        builder.type_by_id_mut(point).unwrap().detail = BtfTypeDetail::Struct(Vec::new());
+        assert!(builder.encode().is_err());
+        assert!(builder.type_by_id_mut(0).is_none());
+    }
+}
@@ -0,0 +1,367 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::{Hash, Hasher},
+};
+
+use crate::{
+    btf::{Btf, BtfKind, BtfType},
+    btf_builder::{self, BtfBuilder},
+    error::{Context as _, Error, Result},
+};
+
+/// The result of [`merge_btf`].
+#[derive(Debug, Clone)]
+pub struct MergedBtf {
+    pub btf: BtfBuilder,
+    /// `remap[input][type_id]` is the id in `btf` of type `type_id` of the `input`th input, for
+    /// rewriting map type ids, func info and CO-RE relocations that refer to the inputs.
+    pub remap: Vec<Vec<u32>>,
+}
+
+fn hash_of(value: &impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Types of several BTFs in one id space, and what is known about their equivalence.
+struct Dedup<'t> {
+    types: &'t [BtfType],
+    /// Encoding of each type with its type ids zeroed, which equivalent types share.
+    shallow: Vec<Vec<u8>>,
+    refs: Vec<Vec<u32>>,
+    /// Structural hash of each type, covering the types it refers to up to the depth at which
+    /// more rounds of hashing stop telling types apart.
+    hashes: Vec<u64>,
+    /// Representative of the class of each type, which is the type itself until it is found
+    /// to be equivalent to an earlier one.
+    canonical: Vec<u32>,
+}
+
+impl<'t> Dedup<'t> {
+    fn new(types: &'t [BtfType]) -> Dedup<'t> {
+        let shallow = types
+            .iter()
+            .map(|btf_type| {
+                let mut btf_type = btf_type.clone();
+                for type_id in btf_type.type_ids_mut() {
+                    *type_id = 0;
+                }
+                let mut encoded = Vec::new();
+                btf_builder::encode_type(&mut encoded, &btf_type);
+                encoded
+            })
+            .collect::<Vec<_>>();
+        let refs = types.iter().map(BtfType::type_ids).collect::<Vec<_>>();
+
+        // refine the hashes with those of the referenced types until no more types are told
+        // apart, as equivalent types get equal hashes in every round
+        let distinct = |hashes: &[u64]| hashes.iter().collect::<HashSet<_>>().len();
+        let mut hashes = shallow.iter().map(hash_of).collect::<Vec<_>>();
+        let mut distinct_count = distinct(&hashes);
+        loop {
+            let next = refs
+                .iter()
+                .enumerate()
+                .map(|(type_id, refs)| {
+                    let ref_hashes = refs
+                        .iter()
+                        .map(|&ref_id| hashes[ref_id as usize])
+                        .collect::<Vec<_>>();
+                    hash_of(&(hashes[type_id], ref_hashes))
+                })
+                .collect::<Vec<_>>();
+            let next_count = distinct(&next);
+            hashes = next;
+            if next_count == distinct_count {
+                break;
+            }
+            distinct_count = next_count;
+        }
+
+        Dedup {
+            types,
+            shallow,
+            refs,
+            hashes,
+            canonical: (0..types.len() as u32).collect(),
+        }
+    }
+
+    /// Assigns every type to the first type it is equivalent to. Candidates are the earlier
+    /// representatives with the same hash, confirmed by comparing the type graphs.
+    fn run(mut self) -> Vec<u32> {
+        let mut representatives: HashMap<u64, Vec<u32>> = HashMap::new();
+        for type_id in 1..self.types.len() as u32 {
+            let candidates = representatives
+                .entry(self.hashes[type_id as usize])
+                .or_default();
+            match candidates
+                .iter()
+                .find(|&&candidate| self.is_equiv(type_id, candidate))
+            {
+                Some(&candidate) => self.canonical[type_id as usize] = candidate,
+                None => candidates.push(type_id),
+            }
+        }
+        self.canonical
+    }
+
+    /// Whether `type_id` and the earlier `candidate` describe the same type. Pairs being
+    /// compared are assumed equivalent, so that types referring to themselves through
+    /// pointers compare equal when their structure matches.
+    fn is_equiv(&self, type_id: u32, candidate: u32) -> bool {
+        let mut assumed = HashSet::new();
+        let mut pending = vec![(type_id, candidate)];
+        while let Some((a, b)) = pending.pop() {
+            let (a, b) = (self.canonical[a as usize], self.canonical[b as usize]);
+            if a == b || !assumed.insert((a, b)) {
+                continue;
+            }
+            let (a_idx, b_idx) = (a as usize, b as usize);
+            // earlier types are final, so different representatives are not equivalent
+            if (a < type_id && b < type_id)
+                || a == 0
+                || b == 0
+                // variables and sections describe storage, which is never shared
+                || matches!(self.types[a_idx].kind, BtfKind::Var | BtfKind::DataSec)
+                || self.hashes[a_idx] != self.hashes[b_idx]
+                || self.shallow[a_idx] != self.shallow[b_idx]
+            {
+                return false;
+            }
+            pending.extend(
+                self.refs[a_idx]
+                    .iter()
+                    .copied()
+                    .zip(self.refs[b_idx].iter().copied()),
+            );
+        }
+        true
+    }
+}
+
+/// Points references to a forward declaration at the struct or union of the same name, when
+/// the types have exactly one such definition. Returns the `(fwd, definition)` pairs.
+fn resolve_fwds(types: &mut [BtfType], canonical: &[u32]) -> Vec<(u32, u32)> {
+    let mut definitions: HashMap<(u32, bool), HashSet<u32>> = HashMap::new();
+    for (type_id, btf_type) in types.iter().enumerate() {
+        if matches!(btf_type.kind, BtfKind::Struct | BtfKind::Union) && btf_type.name_off != 0 {
+            let is_union = btf_type.kind == BtfKind::Union;
+            definitions
+                .entry((btf_type.name_off, is_union))
+                .or_default()
+                .insert(canonical[type_id]);
+        }
+    }
+    let mut resolved = HashMap::new();
+    for (type_id, btf_type) in types.iter().enumerate() {
+        // a fwd's kind_flag tells whether it declares a union
+        if btf_type.kind == BtfKind::Fwd
+            && let Some(definitions) = definitions.get(&(btf_type.name_off, btf_type.kind_flag))
+            && definitions.len() == 1
+            && let Some(&definition) = definitions.iter().next()
+        {
+            resolved.insert(type_id as u32, definition);
+        }
+    }
+    for btf_type in types.iter_mut() {
+        for type_id in btf_type.type_ids_mut() {
+            if let Some(&definition) = resolved.get(type_id) {
+                *type_id = definition;
+            }
+        }
+    }
+    resolved.into_iter().collect()
+}
+
+/// Merges the BTF of several objects into one, deduplicating strings and types, in the
+/// manner of libbpf's `btf__dedup`:
+///
+/// - Strings are stored once.
+/// - Types are equivalent when they have the same kind, names and layout and refer to
+///   equivalent types, which also covers cycles such as a struct pointing to itself. Each
+///   set of equivalent types is kept once, as its first occurrence.
+/// - Forward declarations are replaced by the struct or union they declare when the inputs
+///   define it in exactly one way.
+///
+/// Variables and data sections are never merged. Types keep the order of their first
+/// occurrence, so merging a single BTF without duplicates preserves its ids.
+pub fn merge_btf(inputs: &[&Btf]) -> Result<MergedBtf> {
+    let mut btf = BtfBuilder::new();
+    // the types of all inputs in one id space, with names in the strings of `btf`
+    let mut types = vec![btf.type_by_id(0).context("Missing void type")?.clone()];
+    let mut starts = Vec::with_capacity(inputs.len());
+    for input in inputs {
+        if input.base.is_some() {
+            return Err(Error::InvalidArgument("Cannot merge split BTF".to_string()));
+        }
+        let start = types.len() as u32 - 1;
+        starts.push(start);
+        for (type_id, btf_type) in input.types().skip(1) {
+            let mut btf_type = btf_type.clone();
+            for name_off in btf_type.name_offs_mut() {
+                *name_off = btf.add_string(input.name_by_offset(*name_off)?);
+            }
+            for ref_id in btf_type.type_ids_mut() {
+                if *ref_id >= input.type_count() {
+                    return Err(Error::Parse(format!(
+                        "BTF type {type_id} refers to missing type {ref_id}"
+                    )));
+                }
+                if *ref_id != 0 {
+                    *ref_id += start;
+                }
+            }
+            types.push(btf_type);
+        }
+    }
+
+    let mut canonical = Dedup::new(&types).run();
+    let resolved = resolve_fwds(&mut types, &canonical);
+    if !resolved.is_empty() {
+        // types that differed only in using the fwd or the definition are equivalent now
+        canonical = Dedup::new(&types).run();
+        for (fwd, definition) in resolved {
+            canonical[fwd as usize] = canonical[definition as usize];
+        }
+    }
+
+    let mut new_ids = vec![0; types.len()];
+    let mut next_id = 1;
+    for (type_id, new_id) in new_ids.iter_mut().enumerate().skip(1) {
+        if canonical[type_id] == type_id as u32 {
+            *new_id = next_id;
+            next_id += 1;
+        }
+    }
+    for (type_id, btf_type) in types.into_iter().enumerate().skip(1) {
+        if canonical[type_id] != type_id as u32 {
+            continue;
+        }
+        let mut btf_type = btf_type;
+        for ref_id in btf_type.type_ids_mut() {
+            *ref_id = new_ids[canonical[*ref_id as usize] as usize];
+        }
+        btf.push_type(btf_type)?;
+    }
+
+    let remap = inputs
+        .iter()
+        .zip(starts)
+        .map(|(input, start)| {
+            std::iter::once(0)
+                .chain(
+                    (1..input.type_count())
+                        .map(|type_id| new_ids[canonical[(start + type_id) as usize] as usize]),
+                )
+                .collect()
+        })
+        .collect();
+    Ok(MergedBtf { btf, remap })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{btf::BtfTypeDetail, btf_parser};
+
+    /// Encodes `struct node { <int of val_size bytes> val; struct node *next; }`, with the
+    /// pointer added before the struct it points to.
+    fn linked_list(val_size: u32) -> Vec<u8> {
+        let mut builder = BtfBuilder::new();
+        let val = builder.add_int("val_t", val_size, 0).unwrap();
+        let ptr = builder.add_ref("", BtfKind::Ptr, val + 2).unwrap();
+        let members = [("val", val, 0), ("next", ptr, 64)];
+        builder
+            .add_struct("node", BtfKind::Struct, 16, &members)
+            .unwrap();
+        builder.encode().unwrap()
+    }
+
+    /// Encodes `struct list { struct node *head; }` with `node` only forward declared.
+    fn list_of_fwd() -> Vec<u8> {
+        let mut builder = BtfBuilder::new();
+        let fwd = builder
+            .add_type("node", BtfKind::Fwd, false, 0, BtfTypeDetail::None)
+            .unwrap();
+        let ptr = builder.add_ref("", BtfKind::Ptr, fwd).unwrap();
+        builder
+            .add_struct("list", BtfKind::Struct, 8, &[("head", ptr, 0)])
+            .unwrap();
+        builder.encode().unwrap()
+    }
+
+    fn kinds(btf: &BtfBuilder) -> Vec<BtfKind> {
+        btf.types()
+            .skip(1)
+            .map(|(_, btf_type)| btf_type.kind)
+            .collect()
+    }
+
+    #[test]
+    fn merges_equal_self_referential_structs() {
+        let raw = linked_list(8);
+        let btf = btf_parser::parse_btf(&raw, 0).unwrap();
+        let merged = merge_btf(&[&btf, &btf]).unwrap();
+
+        assert_eq!(merged.btf.type_count(), btf.type_count());
+        assert_eq!(merged.remap[0], [0, 1, 2, 3]);
+        assert_eq!(merged.remap[1], merged.remap[0]);
+        let ptr = merged.btf.type_by_id(2).unwrap();
+        assert_eq!(ptr.size_or_type, 3);
+        assert_eq!(
+            merged
+                .btf
+                .name_of(merged.btf.type_by_id(3).unwrap())
+                .unwrap(),
+            "node"
+        );
+    }
+
+    #[test]
+    fn keeps_structs_that_differ_behind_a_cycle() {
+        let (raw_a, raw_b) = (linked_list(8), linked_list(4));
+        let a = btf_parser::parse_btf(&raw_a, 0).unwrap();
+        let b = btf_parser::parse_btf(&raw_b, 0).unwrap();
+        let merged = merge_btf(&[&a, &b]).unwrap();
+
+        assert_eq!(merged.btf.type_count(), 7);
+        assert_eq!(merged.remap[1], [0, 4, 5, 6]);
+        assert_eq!(merged.btf.type_by_id(5).unwrap().size_or_type, 6);
+    }
+
+    #[test]
+    fn resolves_fwd_to_the_only_definition() {
+        let (raw_list, raw_node) = (list_of_fwd(), linked_list(8));
+        let list = btf_parser::parse_btf(&raw_list, 0).unwrap();
+        let node = btf_parser::parse_btf(&raw_node, 0).unwrap();
+        let merged = merge_btf(&[&list, &node]).unwrap();
+
+        // the fwd and the pointer to it are replaced by the definition and its pointer
+        assert_eq!(merged.remap[0], [0, 4, 1, 2]);
+        assert_eq!(merged.remap[1], [0, 3, 1, 4]);
+        assert_eq!(
+            kinds(&merged.btf),
+            [BtfKind::Ptr, BtfKind::Struct, BtfKind::Int, BtfKind::Struct]
+        );
+        let head = match &merged.btf.type_by_id(2).unwrap().detail {
+            BtfTypeDetail::Struct(members) => members[0].type_id,
+            _ => panic!("list is not a struct"),
+        };
+        assert_eq!(head, 1);
+        assert_eq!(merged.btf.type_by_id(head).unwrap().size_or_type, 4);
+    }
+
+    #[test]
+    fn keeps_fwd_with_several_definitions() {
+        let (raw_list, raw_a, raw_b) = (list_of_fwd(), linked_list(8), linked_list(4));
+        let list = btf_parser::parse_btf(&raw_list, 0).unwrap();
+        let a = btf_parser::parse_btf(&raw_a, 0).unwrap();
+        let b = btf_parser::parse_btf(&raw_b, 0).unwrap();
+        let merged = merge_btf(&[&list, &a, &b]).unwrap();
+
+        assert_eq!(merged.btf.type_by_id(1).unwrap().kind, BtfKind::Fwd);
+        assert_eq!(merged.btf.type_count(), 10);
+    }
+}
@@ -0,0 +1,565 @@
+//! Emits C type definitions from BTF, the equivalent of
+//! `bpftool btf dump file /sys/kernel/btf/vmlinux format c`.
+
+use std::collections::HashMap;
+
+use crate::{
+    btf::{Btf, BtfKind, BtfType, BtfTypeDetail},
+    error::{Error, Result},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EmitState {
+    NotEmitted,
+    Emitting,
+    Emitted,
+}
+
+/// C keeps struct, union and enum tags apart from ordinary identifiers such as typedef names
+/// and enumerators, so name clashes are tracked per namespace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Namespace {
+    Tag,
+    Ident,
+}
+
+/// Explicit padding fields, widest first.
+const PADDING_TYPES: [(&str, u32); 4] = [("long", 64), ("int", 32), ("short", 16), ("char", 8)];
+
+struct CDumper<'b, 'a> {
+    btf: &'b Btf<'a>,
+    out: String,
+    states: Vec<EmitState>,
+    fwd_emitted: Vec<bool>,
+    /// Anonymous types that are used by another type and are defined inline there.
+    referenced: Vec<bool>,
+    type_names: HashMap<u32, String>,
+    name_counts: HashMap<(Namespace, &'a str), usize>,
+}
+
+fn indent(lvl: usize) -> String {
+    "\t".repeat(lvl)
+}
+
+/// Joins a type specifier with the declarator built so far.
+fn join(base: &str, inner: &str) -> String {
+    if inner.is_empty() {
+        base.to_string()
+    } else {
+        format!("{base} {inner}")
+    }
+}
+
+/// Array and function declarators bind tighter than `*`, so pointers to them need parentheses.
+fn parenthesize(inner: &str) -> String {
+    if inner.starts_with('*') {
+        format!("({inner})")
+    } else {
+        inner.to_string()
+    }
+}
+
+/// Type ids that `btf_type` refers to as part of a C declaration.
+fn referenced_types(btf_type: &BtfType) -> Vec<u32> {
+    match (&btf_type.kind, &btf_type.detail) {
+        (
+            BtfKind::Ptr
+            | BtfKind::Typedef
+            | BtfKind::Volatile
+            | BtfKind::Const
+            | BtfKind::Restrict
+            | BtfKind::TypeTag,
+            _,
+        ) => vec![btf_type.size_or_type],
+        (BtfKind::Array, BtfTypeDetail::Array(array)) => vec![array.type_id],
+        (BtfKind::Struct | BtfKind::Union, BtfTypeDetail::Struct(members)) => {
+            members.iter().map(|member| member.type_id).collect()
+        }
+        (BtfKind::FuncProto, BtfTypeDetail::FuncProto(params)) => {
+            std::iter::once(btf_type.size_or_type)
+                .chain(params.iter().map(|param| param.type_id))
+                .collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+impl<'b, 'a> CDumper<'b, 'a> {
+    fn new(btf: &'b Btf<'a>) -> Self {
+        let type_count = btf.type_count() as usize;
+        let mut referenced = vec![false; type_count];
+        for (_, btf_type) in btf.types() {
+            for type_id in referenced_types(btf_type) {
+                if let Some(referenced) = referenced.get_mut(type_id as usize) {
+                    *referenced = true;
+                }
+            }
+        }
+        CDumper {
+            btf,
+            out: String::new(),
+            states: vec![EmitState::NotEmitted; type_count],
+            fwd_emitted: vec![false; type_count],
+            referenced,
+            type_names: HashMap::new(),
+            name_counts: HashMap::new(),
+        }
+    }
+
+    fn get_type(&self, type_id: u32) -> Result<&'b BtfType> {
+        self.btf.get_type(type_id)
+    }
+
+    /// Appends `___<n>` to the n-th distinct use of a name, as libbpf does.
+    fn unique_name(&mut self, namespace: Namespace, name: &'a str) -> String {
+        let count = self.name_counts.entry((namespace, name)).or_default();
+        *count += 1;
+        if *count == 1 {
+            name.to_string()
+        } else {
+            format!("{name}___{count}")
+        }
+    }
+
+    fn type_name(&mut self, type_id: u32) -> Result<String> {
+        if let Some(name) = self.type_names.get(&type_id) {
+            return Ok(name.clone());
+        }
+        let btf_type = self.get_type(type_id)?;
+        let name = self.btf.name_of(btf_type)?;
+        let name = match btf_type.kind {
+            // forward declarations name the same tag as the definition they stand for
+            BtfKind::Fwd | BtfKind::Int | BtfKind::Float => name.to_string(),
+            BtfKind::Typedef => self.unique_name(Namespace::Ident, name),
+            _ => self.unique_name(Namespace::Tag, name),
+        };
+        self.type_names.insert(type_id, name.clone());
+        Ok(name)
+    }
+
+    fn state(&self, type_id: u32) -> EmitState {
+        self.states[type_id as usize]
+    }
+
+    fn set_state(&mut self, type_id: u32, state: EmitState) {
+        self.states[type_id as usize] = state;
+    }
+
+    fn emit_top_level(&mut self, type_id: u32) -> Result<()> {
+        let btf_type = self.get_type(type_id)?;
+        match btf_type.kind {
+            BtfKind::Struct | BtfKind::Union if btf_type.name_off == 0 => Ok(()),
+            // anonymous enums that nothing refers to are only useful for their enumerators
+            BtfKind::Enum | BtfKind::Enum64
+                if btf_type.name_off == 0 && !self.referenced[type_id as usize] =>
+            {
+                if self.state(type_id) == EmitState::NotEmitted {
+                    let def = self.enum_def(type_id, 0)?;
+                    self.out.push_str(&format!("{def};\n\n"));
+                    self.set_state(type_id, EmitState::Emitted);
+                }
+                Ok(())
+            }
+            BtfKind::Struct
+            | BtfKind::Union
+            | BtfKind::Enum
+            | BtfKind::Enum64
+            | BtfKind::Fwd
+            | BtfKind::Typedef => self.emit_type(type_id, true),
+            _ => Ok(()),
+        }
+    }
+
+    /// Makes sure `type_id` can be used in the declarations that follow. Types used by value
+    /// must be fully defined, while a forward declaration is enough for structs and unions
+    /// behind a pointer, which is also how cycles between structs are broken.
+    fn emit_type(&mut self, type_id: u32, by_value: bool) -> Result<()> {
+        let btf_type = self.get_type(type_id)?;
+        match (&btf_type.kind, &btf_type.detail) {
+            (BtfKind::Ptr, _) => self.emit_type(btf_type.size_or_type, false),
+            (BtfKind::Const | BtfKind::Volatile | BtfKind::Restrict | BtfKind::TypeTag, _) => {
+                self.emit_type(btf_type.size_or_type, by_value)
+            }
+            (BtfKind::Array, BtfTypeDetail::Array(array)) => {
+                self.emit_type(array.type_id, by_value)
+            }
+            (BtfKind::FuncProto, BtfTypeDetail::FuncProto(params)) => {
+                self.emit_type(btf_type.size_or_type, false)?;
+                for param in params {
+                    self.emit_type(param.type_id, false)?;
+                }
+                Ok(())
+            }
+            (BtfKind::Enum | BtfKind::Enum64, _) => {
+                if btf_type.name_off != 0 && self.state(type_id) == EmitState::NotEmitted {
+                    let def = self.enum_def(type_id, 0)?;
+                    self.out.push_str(&format!("{def};\n\n"));
+                    self.set_state(type_id, EmitState::Emitted);
+                }
+                Ok(())
+            }
+            (BtfKind::Fwd, _) => self.emit_fwd(type_id),
+            (BtfKind::Struct | BtfKind::Union, BtfTypeDetail::Struct(members)) => {
+                if btf_type.name_off == 0 {
+                    // defined inline, so everything it embeds has to be defined first
+                    for member in members {
+                        self.emit_type(member.type_id, true)?;
+                    }
+                    return Ok(());
+                }
+                match self.state(type_id) {
+                    EmitState::Emitted => Ok(()),
+                    EmitState::Emitting => self.emit_fwd(type_id),
+                    EmitState::NotEmitted if !by_value => self.emit_fwd(type_id),
+                    EmitState::NotEmitted => {
+                        self.set_state(type_id, EmitState::Emitting);
+                        for member in members {
+                            self.emit_type(member.type_id, true)?;
+                        }
+                        let def = self.struct_def(type_id, 0)?;
+                        self.out.push_str(&format!("{def};\n\n"));
+                        self.set_state(type_id, EmitState::Emitted);
+                        Ok(())
+                    }
+                }
+            }
+            (BtfKind::Typedef, _) => {
+                // compiler builtins are already defined, and differently by each compiler
+                if self.btf.name_of(btf_type)? == "__builtin_va_list" {
+                    return Ok(());
+                }
+                let target = btf_type.size_or_type;
+                match self.state(type_id) {
+                    EmitState::Emitting => return Ok(()),
+                    EmitState::Emitted => {}
+                    EmitState::NotEmitted => {
+                        self.set_state(type_id, EmitState::Emitting);
+                        self.emit_type(target, false)?;
+                        let name = self.type_name(type_id)?;
+                        let decl = self.decl(target, &name, 0)?;
+                        self.out.push_str(&format!("typedef {decl};\n\n"));
+                        self.set_state(type_id, EmitState::Emitted);
+                    }
+                }
+                if by_value {
+                    self.emit_type(target, true)?;
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn emit_fwd(&mut self, type_id: u32) -> Result<()> {
+        if self.fwd_emitted[type_id as usize] {
+            return Ok(());
+        }
+        let decl = self.decl(type_id, "", 0)?;
+        self.out.push_str(&format!("{decl};\n\n"));
+        self.fwd_emitted[type_id as usize] = true;
+        Ok(())
+    }
+
+    /// Builds the declaration of `inner` (a name, possibly already wrapped in pointer, array or
+    /// function declarators) as having type `type_id`.
+    fn decl(&mut self, type_id: u32, inner: &str, lvl: usize) -> Result<String> {
+        if type_id == 0 {
+            return Ok(join("void", inner));
+        }
+        let btf_type = self.get_type(type_id)?;
+        match (&btf_type.kind, &btf_type.detail) {
+            (BtfKind::Int | BtfKind::Float | BtfKind::Typedef, _) => {
+                let name = self.type_name(type_id)?;
+                Ok(join(&name, inner))
+            }
+            (BtfKind::Struct | BtfKind::Union | BtfKind::Fwd, _) => {
+                let is_union = match btf_type.kind {
+                    BtfKind::Fwd => btf_type.kind_flag,
+                    kind => kind == BtfKind::Union,
+                };
+                let keyword = if is_union { "union" } else { "struct" };
+                if btf_type.name_off == 0 {
+                    let def = self.struct_def(type_id, lvl)?;
+                    Ok(join(&def, inner))
+                } else {
+                    let name = self.type_name(type_id)?;
+                    Ok(join(&format!("{keyword} {name}"), inner))
+                }
+            }
+            (BtfKind::Enum | BtfKind::Enum64, _) => {
+                if btf_type.name_off == 0 {
+                    let def = self.enum_def(type_id, lvl)?;
+                    Ok(join(&def, inner))
+                } else {
+                    let name = self.type_name(type_id)?;
+                    Ok(join(&format!("enum {name}"), inner))
+                }
+            }
+            (BtfKind::Ptr, _) => self.decl(btf_type.size_or_type, &format!("*{inner}"), lvl),
+            (BtfKind::Const | BtfKind::Volatile | BtfKind::Restrict, _) => {
+                let qualifier = match btf_type.kind {
+                    BtfKind::Const => "const",
+                    BtfKind::Volatile => "volatile",
+                    _ => "restrict",
+                };
+                // a qualified pointer is written `int *const p`, anything else `const int x`
+                let mut target = self.get_type(btf_type.size_or_type)?;
+                while matches!(
+                    target.kind,
+                    BtfKind::Const | BtfKind::Volatile | BtfKind::Restrict | BtfKind::TypeTag
+                ) {
+                    target = self.get_type(target.size_or_type)?;
+                }
+                if target.kind == BtfKind::Ptr {
+                    let inner = join(qualifier, inner);
+                    self.decl(btf_type.size_or_type, &inner, lvl)
+                } else {
+                    let decl = self.decl(btf_type.size_or_type, inner, lvl)?;
+                    // qualifiers on an array apply to its elements, which may repeat them
+                    if decl.starts_with(&format!("{qualifier} ")) {
+                        Ok(decl)
+                    } else {
+                        Ok(format!("{qualifier} {decl}"))
+                    }
+                }
+            }
+            (BtfKind::TypeTag, _) => self.decl(btf_type.size_or_type, inner, lvl),
+            (BtfKind::Array, BtfTypeDetail::Array(array)) => {
+                let inner = format!("{}[{}]", parenthesize(inner), array.nelems);
+                self.decl(array.type_id, &inner, lvl)
+            }
+            (BtfKind::FuncProto, BtfTypeDetail::FuncProto(params)) => {
+                let mut param_decls = Vec::new();
+                for (i, param) in params.iter().enumerate() {
+                    if i == params.len() - 1 && param.type_id == 0 {
+                        param_decls.push("...".to_string());
+                        continue;
+                    }
+                    let name = self.btf.name_by_offset(param.name_off)?;
+                    param_decls.push(self.decl(param.type_id, name, lvl)?);
+                }
+                if param_decls.is_empty() {
+                    param_decls.push("void".to_string());
+                }
+                let inner = format!("{}({})", parenthesize(inner), param_decls.join(", "));
+                self.decl(btf_type.size_or_type, &inner, lvl)
+            }
+            (kind, _) => Err(Error::Parse(format!(
+                "BTF kind {kind:?} cannot appear in a C declaration"
+            ))),
+        }
+    }
+
+    /// Fills the gap between bit offsets `cur_off` and `next_off` with anonymous bitfields, so
+    /// that the compiler lays out the next member where BTF says it is. This follows libbpf's
+    /// `btf_dump_emit_bit_padding`.
+    fn bit_padding(
+        def: &mut String,
+        mut cur_off: u32,
+        next_off: u32,
+        next_align: u32,
+        in_bitfield: bool,
+        lvl: usize,
+    ) {
+        if cur_off >= next_off {
+            return;
+        }
+        let round_up = |off: u32, bits: u32| off.div_ceil(bits) * bits;
+        // find the widest padding type whose natural alignment does not overshoot
+        let (mut pad_type, mut pad_bits) = PADDING_TYPES[PADDING_TYPES.len() - 1];
+        let mut new_off = round_up(cur_off, pad_bits);
+        for (name, bits) in PADDING_TYPES {
+            (pad_type, pad_bits) = (name, bits);
+            new_off = round_up(cur_off, bits);
+            if new_off <= next_off {
+                break;
+            }
+        }
+        if new_off > cur_off && new_off <= next_off {
+            // the compiler does not align on its own if the next member is less strictly
+            // aligned than the padding type, or if the padding would fit in the hole
+            if in_bitfield
+                || (new_off == next_off && round_up(cur_off, next_align * 8) != new_off)
+                || (new_off != next_off && next_off - new_off <= new_off - cur_off)
+            {
+                let bits = if in_bitfield { new_off - cur_off } else { 0 };
+                def.push_str(&format!("\n{}{pad_type}: {bits};", indent(lvl)));
+            }
+            cur_off = new_off;
+        }
+        while cur_off != next_off {
+            let bits = (next_off - cur_off).min(pad_bits);
+            if bits == pad_bits {
+                def.push_str(&format!("\n{}{pad_type}: {pad_bits};", indent(lvl)));
+                cur_off += bits;
+                continue;
+            }
+            // the remainder uses the smallest type that holds it
+            for (name, type_bits) in PADDING_TYPES.iter().rev() {
+                if *type_bits >= bits {
+                    def.push_str(&format!("\n{}{name}: {bits};", indent(lvl)));
+                    cur_off += bits;
+                    break;
+                }
+            }
+        }
+    }
+
+    fn struct_def(&mut self, type_id: u32, lvl: usize) -> Result<String> {
+        let btf_type = self.get_type(type_id)?;
+        let BtfTypeDetail::Struct(members) = &btf_type.detail else {
+            return Err(Error::Parse(format!(
+                "BTF type {type_id} is not a struct or union"
+            )));
+        };
+        let is_struct = btf_type.kind == BtfKind::Struct;
+        let keyword = if is_struct { "struct" } else { "union" };
+        let mut def = if btf_type.name_off == 0 {
+            format!("{keyword} {{")
+        } else {
+            format!("{keyword} {} {{", self.type_name(type_id)?)
+        };
+        let packed = is_struct && self.btf.is_packed(btf_type)?;
+        let align = self.btf.align_of(type_id)?;
+        let mut off = 0;
+        let mut prev_bitfield = false;
+        for member in members {
+            let name = self.btf.name_by_offset(member.name_off)?;
+            let bitfield_size = member.get_bitfield_size(btf_type.kind_flag);
+            let member_off = member.get_offset(btf_type.kind_flag);
+            let member_align = if packed {
+                1
+            } else {
+                self.btf.align_of(member.type_id)?
+            };
+            let in_bitfield = prev_bitfield && bitfield_size != 0;
+            Self::bit_padding(
+                &mut def,
+                off,
+                member_off,
+                member_align,
+                in_bitfield,
+                lvl + 1,
+            );
+            let decl = self.decl(member.type_id, name, lvl + 1)?;
+            def.push_str(&format!("\n{}{decl}", indent(lvl + 1)));
+            if bitfield_size != 0 {
+                def.push_str(&format!(": {bitfield_size}"));
+                off = member_off + bitfield_size;
+                prev_bitfield = true;
+            } else {
+                off = member_off + self.btf.size_of(member.type_id)? * 8;
+                prev_bitfield = false;
+            }
+            def.push(';');
+        }
+        if is_struct {
+            Self::bit_padding(
+                &mut def,
+                off,
+                btf_type.size_or_type * 8,
+                align,
+                false,
+                lvl + 1,
+            );
+        }
+        // keep `struct empty {}` on one line
+        if def.ends_with('{') {
+            def.push('}');
+        } else {
+            def.push_str(&format!("\n{}}}", indent(lvl)));
+        }
+        if packed {
+            def.push_str(" __attribute__((packed))");
+        }
+        Ok(def)
+    }
+
+    fn enum_def(&mut self, type_id: u32, lvl: usize) -> Result<String> {
+        let btf_type = self.get_type(type_id)?;
+        let signed = btf_type.is_signed_enum();
+        let values: Vec<(u32, u64)> = match &btf_type.detail {
+            BtfTypeDetail::Enum(values) => values
+                .iter()
+                .map(|value| (value.name_off, value.val as i64 as u64))
+                .collect(),
+            BtfTypeDetail::Enum64(values) => values
+                .iter()
+                .map(|value| (value.name_off, value.get_value()))
+                .collect(),
+            _ => {
+                return Err(Error::Parse(format!("BTF type {type_id} is not an enum")));
+            }
+        };
+        let mut def = if btf_type.name_off == 0 {
+            "enum".to_string()
+        } else {
+            format!("enum {}", self.type_name(type_id)?)
+        };
+        // an enum without values is a forward declaration
+        if values.is_empty() {
+            return Ok(def);
+        }
+        def.push_str(" {");
+        let mut fits_in_32_bits = true;
+        for (name_off, value) in values {
+            let name = self.btf.name_by_offset(name_off)?;
+            let name = self.unique_name(Namespace::Ident, name);
+            let value = if signed {
+                fits_in_32_bits &= i32::try_from(value as i64).is_ok();
+                (value as i64).to_string()
+            } else if btf_type.kind == BtfKind::Enum {
+                // 32-bit enum values are stored sign-extended above
+                (value as u32).to_string()
+            } else {
+                fits_in_32_bits &= u32::try_from(value).is_ok();
+                if value > i64::MAX as u64 {
+                    format!("{value}ULL")
+                } else {
+                    value.to_string()
+                }
+            };
+            def.push_str(&format!("\n{}{name} = {value},", indent(lvl + 1)));
+        }
+        def.push_str(&format!("\n{}}}", indent(lvl)));
+        // the compiler sizes enums by their values, so other sizes have to be forced
+        match btf_type.size_or_type {
+            1 => def.push_str(" __attribute__((mode(byte)))"),
+            2 => def.push_str(" __attribute__((mode(HI)))"),
+            8 if fits_in_32_bits => def.push_str(" __attribute__((mode(word)))"),
+            _ => {}
+        }
+        Ok(def)
+    }
+}
+
+/// Emits C definitions for every type in `btf`, dependencies first, with forward declarations
+/// breaking cycles between structs. Duplicate names get a `___<n>` suffix.
+pub fn dump_c(btf: &Btf) -> Result<String> {
+    let mut dumper = CDumper::new(btf);
+    for type_id in 1..btf.type_count() {
+        dumper.emit_top_level(type_id)?;
+    }
+    Ok(dumper.out)
+}
+
+/// Emits a `vmlinux.h` style header: the output of [`dump_c`] with an include guard and with
+/// clang's `preserve_access_index` applied to every record for CO-RE.
+pub fn dump_vmlinux_header(btf: &Btf) -> Result<String> {
+    let types = dump_c(btf)?;
+    Ok(format!(
+        "#ifndef __VMLINUX_H__
+#define __VMLINUX_H__
+
+#ifndef BPF_NO_PRESERVE_ACCESS_INDEX
+#pragma clang attribute push (__attribute__((preserve_access_index)), apply_to = record)
+#endif
+
+{types}#ifndef BPF_NO_PRESERVE_ACCESS_INDEX
+#pragma clang attribute pop
+#endif
+
+#endif /* __VMLINUX_H__ */
+"
+    ))
+}
@@ -1,9 +1,12 @@
-use anyhow::{bail, Context as _, Result};
+use std::{mem::offset_of, sync::OnceLock};
+
+use crate::error::{Context as _, Error, Result};
 
 use crate::{
     btf::{
-        BpfCoreRelo, BpfCoreReloKind, Btf, BtfExt, BtfExtHeader, BtfExtInfoSec, BtfHeader, BtfKind,
-        BtfMember, BtfType, BtfTypeDetail,
+        BpfCoreRelo, BpfCoreReloKind, BpfFuncInfo, BpfLineInfo, Btf, BtfArray, BtfDeclTag, BtfEnum,
+        BtfEnum64, BtfExt, BtfExtHeader, BtfExtInfoSec, BtfHeader, BtfInt, BtfKind, BtfLinkage,
+        BtfMember, BtfParam, BtfType, BtfTypeDetail, BtfVar, BtfVarSecinfo,
     },
     common,
 };
@@ -12,7 +15,7 @@ fn parse_btf_header(data: &[u8], offset: usize) -> Result<&BtfHeader> {
     let btf_header =
         common::read_struct::<BtfHeader>(data, offset).context("File too small for BTF header")?;
     if btf_header.magic != 0xeb9f {
-        bail!("Not a BTF file");
+        return Err(Error::Parse("Not a BTF file".to_string()));
     }
     Ok(btf_header)
 }
@@ -29,7 +32,7 @@ fn parse_btf_string_section<'a>(
     if end <= data.len() {
         Ok(&data[start..end])
     } else {
-        bail!("String section out of bounds");
+        Err(Error::Parse("String section out of bounds".to_string()))
     }
 }
 
@@ -43,15 +46,7 @@ fn parse_btf_type_section<'a>(
     let mut start = type_offset + offset;
     let end = start + type_size;
     if end <= data.len() {
-        let dummy = BtfType {
-            name_off: 0,
-            vlen: 0,
-            kind: BtfKind::Int,
-            kind_flag: false,
-            size_or_type: 0,
-            detail: BtfTypeDetail::None,
-        };
-        let mut types = vec![dummy];
+        let mut types = Vec::new();
         while start < end {
             let name_off =
                 *common::read_struct::<u32>(data, start).context("Failed to read name offset")?;
@@ -66,13 +61,18 @@ fn parse_btf_type_section<'a>(
 
             let detail = match kind {
                 BtfKind::Int => {
+                    let int =
+                        *common::read_struct::<u32>(data, start).context("Failed to read int")?;
                     start += std::mem::size_of::<u32>();
-                    BtfTypeDetail::Ignored
+                    BtfTypeDetail::Int(BtfInt::from(int))
                 }
                 BtfKind::Ptr => BtfTypeDetail::None,
                 BtfKind::Array => {
-                    start += std::mem::size_of::<u32>() * 3;
-                    BtfTypeDetail::Ignored
+                    let btf_array = common::read_struct::<BtfArray>(data, start)
+                        .context("Failed to read array")?
+                        .clone();
+                    start += std::mem::size_of::<BtfArray>();
+                    BtfTypeDetail::Array(btf_array)
                 }
                 BtfKind::Struct | BtfKind::Union => {
                     let mut members = Vec::new();
@@ -86,36 +86,71 @@ fn parse_btf_type_section<'a>(
                     BtfTypeDetail::Struct(members)
                 }
                 BtfKind::Enum => {
-                    start += std::mem::size_of::<u32>() * 2 * vlen as usize;
-                    BtfTypeDetail::Ignored
+                    let mut values = Vec::new();
+                    for _ in 0..vlen {
+                        let btf_enum = common::read_struct::<BtfEnum>(data, start)
+                            .context("Failed to read enum value")?
+                            .clone();
+                        values.push(btf_enum);
+                        start += std::mem::size_of::<BtfEnum>();
+                    }
+                    BtfTypeDetail::Enum(values)
                 }
                 BtfKind::Fwd
                 | BtfKind::Typedef
                 | BtfKind::Volatile
                 | BtfKind::Const
-                | BtfKind::Restrict
-                | BtfKind::Func => BtfTypeDetail::None,
+                | BtfKind::Restrict => BtfTypeDetail::None,
+                BtfKind::Func => BtfTypeDetail::Func(BtfLinkage::try_from(vlen as u32)?),
                 BtfKind::FuncProto => {
-                    start += std::mem::size_of::<u32>() * 2 * vlen as usize;
-                    BtfTypeDetail::Ignored
+                    let mut params = Vec::new();
+                    for _ in 0..vlen {
+                        let btf_param = common::read_struct::<BtfParam>(data, start)
+                            .context("Failed to read function parameter")?
+                            .clone();
+                        params.push(btf_param);
+                        start += std::mem::size_of::<BtfParam>();
+                    }
+                    BtfTypeDetail::FuncProto(params)
                 }
                 BtfKind::Var => {
+                    let linkage = *common::read_struct::<u32>(data, start)
+                        .context("Failed to read variable linkage")?;
                     start += std::mem::size_of::<u32>();
-                    BtfTypeDetail::Ignored
+                    BtfTypeDetail::Var(BtfVar {
+                        linkage: BtfLinkage::try_from(linkage)?,
+                    })
                 }
                 BtfKind::DataSec => {
-                    start += std::mem::size_of::<u32>() * 3 * vlen as usize;
-                    BtfTypeDetail::Ignored
+                    let mut vars = Vec::new();
+                    for _ in 0..vlen {
+                        let var_secinfo = common::read_struct::<BtfVarSecinfo>(data, start)
+                            .context("Failed to read datasec variable")?
+                            .clone();
+                        vars.push(var_secinfo);
+                        start += std::mem::size_of::<BtfVarSecinfo>();
+                    }
+                    BtfTypeDetail::DataSec(vars)
                 }
                 BtfKind::Float => BtfTypeDetail::None,
                 BtfKind::DeclTag => {
-                    start += std::mem::size_of::<u32>();
-                    BtfTypeDetail::Ignored
+                    let decl_tag = common::read_struct::<BtfDeclTag>(data, start)
+                        .context("Failed to read decl tag")?
+                        .clone();
+                    start += std::mem::size_of::<BtfDeclTag>();
+                    BtfTypeDetail::DeclTag(decl_tag)
                 }
                 BtfKind::TypeTag => BtfTypeDetail::None,
                 BtfKind::Enum64 => {
-                    start += std::mem::size_of::<u32>() * 3 * vlen as usize;
-                    BtfTypeDetail::Ignored
+                    let mut values = Vec::new();
+                    for _ in 0..vlen {
+                        let btf_enum64 = common::read_struct::<BtfEnum64>(data, start)
+                            .context("Failed to read enum64 value")?
+                            .clone();
+                        values.push(btf_enum64);
+                        start += std::mem::size_of::<BtfEnum64>();
+                    }
+                    BtfTypeDetail::Enum64(values)
                 }
             };
 
@@ -129,15 +164,41 @@ fn parse_btf_type_section<'a>(
             });
         }
         if start != end {
-            bail!("Type section size mismatch");
+            return Err(Error::Parse("Type section size mismatch".to_string()));
         }
         Ok(types)
     } else {
-        bail!("Type section out of bounds");
+        Err(Error::Parse("Type section out of bounds".to_string()))
     }
 }
 
 pub fn parse_btf(data: &[u8], offset: usize) -> Result<Btf<'_>> {
+    let btf_header = parse_btf_header(data, offset)?;
+    let offset = offset + btf_header.hdr_len as usize;
+    let str_section = parse_btf_string_section(data, offset, btf_header)?;
+    // type id 0 is void
+    let void = BtfType {
+        name_off: 0,
+        vlen: 0,
+        kind: BtfKind::Int,
+        kind_flag: false,
+        size_or_type: 0,
+        detail: BtfTypeDetail::None,
+    };
+    let mut type_section = vec![void];
+    type_section.extend(parse_btf_type_section(data, offset, btf_header)?);
+    Ok(Btf {
+        header: btf_header,
+        string_section: str_section,
+        type_section,
+        base: None,
+        name_index: OnceLock::new(),
+    })
+}
+
+/// Parses split BTF such as `/sys/kernel/btf/<module>`, whose type ids and string offsets
+/// continue those of `base`.
+pub fn parse_split_btf<'a>(data: &'a [u8], offset: usize, base: &'a Btf<'a>) -> Result<Btf<'a>> {
     let btf_header = parse_btf_header(data, offset)?;
     let offset = offset + btf_header.hdr_len as usize;
     let str_section = parse_btf_string_section(data, offset, btf_header)?;
@@ -146,86 +207,158 @@ pub fn parse_btf(data: &[u8], offset: usize) -> Result<Btf<'_>> {
         header: btf_header,
         string_section: str_section,
         type_section,
+        base: Some(base),
+        name_index: OnceLock::new(),
     })
 }
 
-fn parse_btf_ext_header(data: &[u8], offset: usize) -> Result<&BtfExtHeader> {
-    let btf_ext_header = common::read_struct::<BtfExtHeader>(data, offset)
-        .context("File too small for BTF ext header")?;
-    if btf_ext_header.magic != 0xeb9f {
-        bail!("Not a BTF ext section");
+/// Parses the `.BTF.ext` header. The first version of the header ends after `line_info_len`;
+/// `core_relo_off` and `core_relo_len` were appended later and read as 0 when absent.
+fn parse_btf_ext_header(data: &[u8], offset: usize) -> Result<BtfExtHeader> {
+    let magic =
+        *common::read_struct::<u16>(data, offset).context("File too small for BTF ext header")?;
+    if magic != 0xeb9f {
+        return Err(Error::Parse("Not a BTF ext section".to_string()));
     }
-    if btf_ext_header.hdr_len != size_of::<BtfExtHeader>() as u32 {
-        bail!("Require core_relo part");
+    let hdr_len = *common::read_struct::<u32>(data, offset + 4)
+        .context("File too small for BTF ext header")?;
+    if (hdr_len as usize) < offset_of!(BtfExtHeader, core_relo_off) {
+        return Err(Error::Parse(format!(
+            "BTF ext header too small: {hdr_len} bytes"
+        )));
     }
-    Ok(btf_ext_header)
+    let field = |field_offset: usize| -> Result<u32> {
+        if field_offset + 4 > hdr_len as usize {
+            return Ok(0);
+        }
+        common::read_struct::<u32>(data, offset + field_offset)
+            .copied()
+            .context("File too small for BTF ext header")
+    };
+    Ok(BtfExtHeader {
+        magic,
+        version: data[offset + 2],
+        flags: data[offset + 3],
+        hdr_len,
+        func_info_off: field(offset_of!(BtfExtHeader, func_info_off))?,
+        func_info_len: field(offset_of!(BtfExtHeader, func_info_len))?,
+        line_info_off: field(offset_of!(BtfExtHeader, line_info_off))?,
+        line_info_len: field(offset_of!(BtfExtHeader, line_info_len))?,
+        core_relo_off: field(offset_of!(BtfExtHeader, core_relo_off))?,
+        core_relo_len: field(offset_of!(BtfExtHeader, core_relo_len))?,
+    })
 }
 
-fn parse_btf_ext_core_relo<'a>(
-    data: &'a [u8],
+/// Parses one `.BTF.ext` part: a record size followed by a list of per-section records.
+/// Records may be larger than the fields known here, in which case the rest is skipped.
+fn parse_btf_ext_info<T>(
+    data: &[u8],
     offset: usize,
-    btf_ext_header: &'a BtfExtHeader,
-) -> Result<Vec<BtfExtInfoSec<BpfCoreRelo>>> {
-    let core_relo_offset = btf_ext_header.core_relo_off as usize;
-    let core_relo_size = btf_ext_header.core_relo_len as usize;
-    let mut start = core_relo_offset + offset;
-    let end = start + core_relo_size;
-    if end <= data.len() {
-        let mut info_sections = Vec::new();
-        // while start < end {
-        // }
-        let core_relo_rec_size = *common::read_struct::<u32>(data, start)
-            .context("Failed to read core relo record size")?;
-        if core_relo_rec_size != 16 {
-            bail!("expected core relo record size to be 16")
-        }
-        start += 4;
-        while start < end {
-            let sec_name_off = *common::read_struct::<u32>(data, start)
-                .context("Failed to read section name offset")?;
-            let num_info = *common::read_struct::<u32>(data, start + 4)
-                .context("Failed to read number of info")?;
-            start += 8;
-            let mut relocations = Vec::new();
-            for _ in 0..num_info {
-                let insn_off = *common::read_struct::<u32>(data, start)
-                    .context("Failed to read instruction offset")?;
-                let type_id = *common::read_struct::<u32>(data, start + 4)
-                    .context("Failed to read type ID")?;
-                let access_str_off = *common::read_struct::<u32>(data, start + 8)
-                    .context("Failed to read access string offset")?;
-                let kind = *common::read_struct::<u32>(data, start + 12)
-                    .context("Failed to read relocation kind")?;
-                let kind =
-                    BpfCoreReloKind::try_from(kind).context("Failed to parse relocation kind")?;
-                relocations.push(BpfCoreRelo {
-                    insn_off,
-                    type_id,
-                    access_str_off,
-                    kind,
-                });
-                start += 16;
-            }
-            info_sections.push(BtfExtInfoSec {
-                sec_name_off,
-                data: relocations,
-            });
+    info_off: u32,
+    info_len: u32,
+    min_rec_size: usize,
+    what: &str,
+    parse_record: impl Fn(usize) -> Result<T>,
+) -> Result<Vec<BtfExtInfoSec<T>>> {
+    let mut info_sections = Vec::new();
+    if info_len == 0 {
+        return Ok(info_sections);
+    }
+    let mut start = info_off as usize + offset;
+    let end = start + info_len as usize;
+    if end > data.len() {
+        return Err(Error::Parse(format!("{what} section out of bounds")));
+    }
+    let rec_size = *common::read_struct::<u32>(data, start)
+        .with_context(|| format!("Failed to read {what} record size"))? as usize;
+    if rec_size < min_rec_size || !rec_size.is_multiple_of(4) {
+        return Err(Error::Parse(format!(
+            "Invalid {what} record size {rec_size}"
+        )));
+    }
+    start += 4;
+    while start < end {
+        let sec_name_off = *common::read_struct::<u32>(data, start)
+            .context("Failed to read section name offset")?;
+        let num_info = *common::read_struct::<u32>(data, start + 4)
+            .context("Failed to read number of info")?;
+        start += 8;
+        if start + num_info as usize * rec_size > end {
+            return Err(Error::Parse(format!("{what} records out of bounds")));
         }
-        if start != end {
-            bail!("Core relocation section size mismatch");
+        let mut records = Vec::new();
+        for _ in 0..num_info {
+            records.push(parse_record(start)?);
+            start += rec_size;
         }
-        Ok(info_sections)
-    } else {
-        bail!("Core relocation section out of bounds");
+        info_sections.push(BtfExtInfoSec {
+            sec_name_off,
+            data: records,
+        });
+    }
+    if start != end {
+        return Err(Error::Parse(format!("{what} section size mismatch")));
     }
+    Ok(info_sections)
 }
 
-pub fn parse_btf_ext(data: &[u8], offset: usize) -> Result<BtfExt<'_>> {
+pub fn parse_btf_ext(data: &[u8], offset: usize) -> Result<BtfExt> {
     let btf_ext_header = parse_btf_ext_header(data, offset)?;
     let offset = offset + btf_ext_header.hdr_len as usize;
-    let relocations = parse_btf_ext_core_relo(data, offset, btf_ext_header)?;
+    let func_info = parse_btf_ext_info(
+        data,
+        offset,
+        btf_ext_header.func_info_off,
+        btf_ext_header.func_info_len,
+        size_of::<BpfFuncInfo>(),
+        "Func info",
+        |start| {
+            common::read_struct::<BpfFuncInfo>(data, start)
+                .cloned()
+                .context("Failed to read func info")
+        },
+    )?;
+    let line_info = parse_btf_ext_info(
+        data,
+        offset,
+        btf_ext_header.line_info_off,
+        btf_ext_header.line_info_len,
+        size_of::<BpfLineInfo>(),
+        "Line info",
+        |start| {
+            common::read_struct::<BpfLineInfo>(data, start)
+                .cloned()
+                .context("Failed to read line info")
+        },
+    )?;
+    let relocations = parse_btf_ext_info(
+        data,
+        offset,
+        btf_ext_header.core_relo_off,
+        btf_ext_header.core_relo_len,
+        16,
+        "Core relocation",
+        |start| {
+            let insn_off = *common::read_struct::<u32>(data, start)
+                .context("Failed to read instruction offset")?;
+            let type_id =
+                *common::read_struct::<u32>(data, start + 4).context("Failed to read type ID")?;
+            let access_str_off = *common::read_struct::<u32>(data, start + 8)
+                .context("Failed to read access string offset")?;
+            let kind = *common::read_struct::<u32>(data, start + 12)
+                .context("Failed to read relocation kind")?;
+            Ok(BpfCoreRelo {
+                insn_off,
+                type_id,
+                access_str_off,
+                kind: BpfCoreReloKind::try_from(kind)?,
+            })
+        },
+    )?;
     Ok(BtfExt {
         header: btf_ext_header,
+        func_info_part: func_info,
+        line_info_part: line_info,
         core_relo_part: relocations,
     })
 }
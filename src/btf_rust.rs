@@ -0,0 +1,610 @@
+//! Generates `#[repr(C)]` Rust definitions from BTF, so that userspace code reading map keys,
+//! values or events shares the layout of the BPF program. The output is meant to be written to
+//! `OUT_DIR` by a build script and pulled in with `include!`:
+//!
+//! ```ignore
+//! let elf = elf_parser::parse_elf("prog.o")?;
+//! let btf = btf_parser::parse_btf(elf.get_section_body(".BTF").unwrap(), 0)?;
+//! let code = btf_rust::dump_rust(&btf, &["event"])?;
+//! std::fs::write(Path::new(&std::env::var("OUT_DIR")?).join("types.rs"), code)?;
+//! ```
+//!
+//! Pointers become `u64`, since their values are only meaningful inside the kernel. C enums
+//! become newtypes with associated constants rather than Rust enums, so that reading a value
+//! the enum does not list is not undefined behaviour. Bitfields are kept in byte arrays with
+//! accessor methods, which assume a little-endian target.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    btf::{Btf, BtfKind, BtfType, BtfTypeDetail, BTF_INT_SIGNED},
+    error::{Error, Result},
+};
+
+const ALLOW: &str =
+    "#[allow(non_camel_case_types, non_snake_case, non_upper_case_globals, dead_code)]";
+
+const BITFIELD_HELPERS: &str = "#[allow(dead_code)]
+fn __bitfield_get(storage: &[u8], bit_offset: usize, bit_width: usize) -> u64 {
+    let mut value = 0u64;
+    for i in 0..bit_width {
+        let bit = bit_offset + i;
+        if storage[bit / 8] & (1 << (bit % 8)) != 0 {
+            value |= 1 << i;
+        }
+    }
+    value
+}
+
+#[allow(dead_code)]
+fn __bitfield_set(storage: &mut [u8], bit_offset: usize, bit_width: usize, value: u64) {
+    for i in 0..bit_width {
+        let bit = bit_offset + i;
+        let mask = 1 << (bit % 8);
+        if value & (1 << i) != 0 {
+            storage[bit / 8] |= mask;
+        } else {
+            storage[bit / 8] &= !mask;
+        }
+    }
+}
+
+";
+
+/// Type names that would shadow Rust primitives, such as the kernel's `typedef __u8 u8`.
+const PRIMITIVE_NAMES: [&str; 17] = [
+    "bool", "char", "str", "f32", "f64", "i8", "i16", "i32", "i64", "i128", "isize", "u8", "u16",
+    "u32", "u64", "u128", "usize",
+];
+
+const KEYWORDS: &[&str] = &[
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "do", "dyn",
+    "else", "enum", "extern", "false", "final", "fn", "for", "gen", "if", "impl", "in", "let",
+    "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref", "return",
+    "static", "struct", "trait", "true", "try", "type", "typeof", "unsafe", "unsized", "use",
+    "virtual", "where", "while", "yield",
+];
+
+/// Turns a C identifier into a Rust one.
+fn escape(name: &str) -> String {
+    match name {
+        // these cannot be raw identifiers
+        "self" | "Self" | "super" | "crate" | "_" => format!("{name}_"),
+        _ if KEYWORDS.contains(&name) => format!("r#{name}"),
+        _ => name.to_string(),
+    }
+}
+
+/// Rust type of a BTF int, or `None` for sizes Rust has no integer for.
+fn int_type(size: u32, signed: bool) -> Option<&'static str> {
+    Some(match (size, signed) {
+        (1, true) => "i8",
+        (1, false) => "u8",
+        (2, true) => "i16",
+        (2, false) => "u16",
+        (4, true) => "i32",
+        (4, false) => "u32",
+        (8, true) => "i64",
+        (8, false) => "u64",
+        (16, true) => "i128",
+        (16, false) => "u128",
+        _ => return None,
+    })
+}
+
+struct RustDumper<'b, 'a> {
+    btf: &'b Btf<'a>,
+    out: String,
+    /// Types to define, in the order they were reached.
+    items: Vec<u32>,
+    visited: HashSet<u32>,
+    /// Typedefs that are not emitted; uses refer to their target instead.
+    aliases: HashSet<u32>,
+    type_names: HashMap<u32, String>,
+    name_counts: HashMap<String, usize>,
+    anon_counts: HashMap<String, usize>,
+    uses_bitfields: bool,
+}
+
+impl<'b, 'a> RustDumper<'b, 'a> {
+    fn new(btf: &'b Btf<'a>) -> Self {
+        RustDumper {
+            btf,
+            out: String::new(),
+            items: Vec::new(),
+            visited: HashSet::new(),
+            aliases: HashSet::new(),
+            type_names: HashMap::new(),
+            name_counts: HashMap::new(),
+            anon_counts: HashMap::new(),
+            uses_bitfields: false,
+        }
+    }
+
+    /// Structs, unions, enums and type aliases share one namespace in Rust, so repeated names
+    /// get a `___<n>` suffix like in the C dump.
+    fn unique_name(&mut self, name: &str) -> String {
+        let count = self.name_counts.entry(name.to_string()).or_default();
+        *count += 1;
+        if *count == 1 {
+            escape(name)
+        } else {
+            format!("{name}___{count}")
+        }
+    }
+
+    /// Names an anonymous struct, union or enum after the type that contains it.
+    fn anon_name(&mut self, parent: &str) -> String {
+        let count = self.anon_counts.entry(parent.to_string()).or_default();
+        *count += 1;
+        let name = format!("{}__anon_{count}", parent.trim_start_matches("r#"));
+        self.unique_name(&name)
+    }
+
+    fn name_type(&mut self, type_id: u32, parent: &str) -> Result<()> {
+        if self.type_names.contains_key(&type_id) {
+            return Ok(());
+        }
+        let btf_type = self.btf.get_type(type_id)?;
+        let name = if btf_type.name_off == 0 {
+            self.anon_name(parent)
+        } else {
+            let name = self.btf.name_of(btf_type)?;
+            self.unique_name(name)
+        };
+        self.type_names.insert(type_id, name);
+        Ok(())
+    }
+
+    /// Whether a typedef can be emitted at all: function types and opaque structs have no
+    /// by-value Rust equivalent.
+    fn is_representable(&self, type_id: u32) -> Result<bool> {
+        if type_id == 0 {
+            return Ok(false);
+        }
+        let btf_type = self.btf.resolve(type_id)?;
+        Ok(!matches!(btf_type.kind, BtfKind::FuncProto | BtfKind::Fwd))
+    }
+
+    fn collect_root(&mut self, type_id: u32) -> Result<()> {
+        let btf_type = self.btf.get_type(type_id)?;
+        if btf_type.kind == BtfKind::Typedef && !self.is_representable(btf_type.size_or_type)? {
+            return Ok(());
+        }
+        self.collect(type_id, "")
+    }
+
+    /// Registers `type_id` and every type it contains by value for emission.
+    fn collect(&mut self, type_id: u32, parent: &str) -> Result<()> {
+        if type_id == 0 {
+            return Err(Error::Parse("void cannot be used by value".to_string()));
+        }
+        let btf_type = self.btf.get_type(type_id)?;
+        match (&btf_type.kind, &btf_type.detail) {
+            (BtfKind::Int | BtfKind::Float | BtfKind::Ptr, _) => Ok(()),
+            (BtfKind::Const | BtfKind::Volatile | BtfKind::Restrict | BtfKind::TypeTag, _) => {
+                self.collect(btf_type.size_or_type, parent)
+            }
+            (BtfKind::Array, BtfTypeDetail::Array(array)) => self.collect(array.type_id, parent),
+            (BtfKind::Typedef, _) => {
+                if !self.visited.insert(type_id) {
+                    return Ok(());
+                }
+                let name = self.btf.name_of(btf_type)?;
+                let mut target_id = btf_type.size_or_type;
+                let mut target = self.btf.get_type(target_id)?;
+                while matches!(
+                    target.kind,
+                    BtfKind::Const | BtfKind::Volatile | BtfKind::Restrict | BtfKind::TypeTag
+                ) {
+                    target_id = target.size_or_type;
+                    target = self.btf.get_type(target_id)?;
+                }
+                let is_record = matches!(
+                    target.kind,
+                    BtfKind::Struct | BtfKind::Union | BtfKind::Enum | BtfKind::Enum64
+                );
+                if PRIMITIVE_NAMES.contains(&name) {
+                    self.aliases.insert(type_id);
+                } else if is_record && target.name_off == 0 {
+                    // `typedef struct { ... } name;` names the struct itself
+                    if !self.type_names.contains_key(&target_id) {
+                        let name = self.unique_name(name);
+                        self.type_names.insert(target_id, name);
+                    }
+                    self.aliases.insert(type_id);
+                } else if is_record && self.btf.name_of(target)? == name {
+                    // `typedef struct name name;`
+                    self.aliases.insert(type_id);
+                } else {
+                    self.name_type(type_id, parent)?;
+                    self.items.push(type_id);
+                }
+                self.collect(btf_type.size_or_type, name)
+            }
+            (BtfKind::Struct | BtfKind::Union, BtfTypeDetail::Struct(members)) => {
+                if !self.visited.insert(type_id) {
+                    return Ok(());
+                }
+                self.name_type(type_id, parent)?;
+                self.items.push(type_id);
+                let name = self.type_names[&type_id].clone();
+                for member in members {
+                    self.collect(member.type_id, &name)?;
+                }
+                Ok(())
+            }
+            (BtfKind::Enum | BtfKind::Enum64, _) => {
+                if self.visited.insert(type_id) {
+                    self.name_type(type_id, parent)?;
+                    self.items.push(type_id);
+                }
+                Ok(())
+            }
+            (kind, _) => Err(Error::Parse(format!(
+                "BTF kind {kind:?} cannot be used by value"
+            ))),
+        }
+    }
+
+    fn rust_type(&self, type_id: u32) -> Result<String> {
+        let btf_type = self.btf.get_type(type_id)?;
+        match (&btf_type.kind, &btf_type.detail) {
+            (BtfKind::Int, BtfTypeDetail::Int(int)) => {
+                let signed = int.encoding & BTF_INT_SIGNED != 0;
+                Ok(int_type(btf_type.size_or_type, signed)
+                    .map_or_else(|| format!("[u8; {}]", btf_type.size_or_type), String::from))
+            }
+            (BtfKind::Float, _) => Ok(match btf_type.size_or_type {
+                4 => "f32".to_string(),
+                8 => "f64".to_string(),
+                size => format!("[u8; {size}]"),
+            }),
+            (BtfKind::Ptr, _) => Ok("u64".to_string()),
+            (BtfKind::Array, BtfTypeDetail::Array(array)) => Ok(format!(
+                "[{}; {}]",
+                self.rust_type(array.type_id)?,
+                array.nelems
+            )),
+            (BtfKind::Const | BtfKind::Volatile | BtfKind::Restrict | BtfKind::TypeTag, _) => {
+                self.rust_type(btf_type.size_or_type)
+            }
+            (BtfKind::Typedef, _) if self.aliases.contains(&type_id) => {
+                self.rust_type(btf_type.size_or_type)
+            }
+            _ => self
+                .type_names
+                .get(&type_id)
+                .cloned()
+                .ok_or_else(|| Error::Parse(format!("BTF type {type_id} has no Rust name"))),
+        }
+    }
+
+    /// Underlying integer type of an enum newtype.
+    fn enum_repr(btf_type: &BtfType) -> Result<&'static str> {
+        int_type(btf_type.size_or_type, btf_type.is_signed_enum())
+            .ok_or_else(|| Error::Parse(format!("Unsupported enum size {}", btf_type.size_or_type)))
+    }
+
+    fn emit_typedef(&mut self, type_id: u32) -> Result<()> {
+        let btf_type = self.btf.get_type(type_id)?;
+        let name = &self.type_names[&type_id];
+        let target = self.rust_type(btf_type.size_or_type)?;
+        self.out
+            .push_str(&format!("{ALLOW}\npub type {name} = {target};\n\n"));
+        Ok(())
+    }
+
+    fn emit_enum(&mut self, type_id: u32) -> Result<()> {
+        let btf_type = self.btf.get_type(type_id)?;
+        let name = self.type_names[&type_id].clone();
+        let repr = Self::enum_repr(btf_type)?;
+        let values: Vec<(u32, u64)> = match &btf_type.detail {
+            BtfTypeDetail::Enum(values) => values
+                .iter()
+                .map(|value| (value.name_off, value.val as i64 as u64))
+                .collect(),
+            BtfTypeDetail::Enum64(values) => values
+                .iter()
+                .map(|value| (value.name_off, value.get_value()))
+                .collect(),
+            _ => return Err(Error::Parse(format!("BTF type {type_id} is not an enum"))),
+        };
+        let mut item = format!(
+            "{ALLOW}\n#[repr(transparent)]\n\
+             #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]\n\
+             pub struct {name}(pub {repr});\n\n"
+        );
+        if !values.is_empty() {
+            item.push_str(&format!("{ALLOW}\nimpl {name} {{\n"));
+            for (name_off, value) in values {
+                let value_name = escape(self.btf.name_by_offset(name_off)?);
+                // values are stored sign-extended, so truncating gives the repr's bit pattern
+                let value = match repr {
+                    "i8" => (value as i8).to_string(),
+                    "i16" => (value as i16).to_string(),
+                    "i32" => (value as i32).to_string(),
+                    "i64" => (value as i64).to_string(),
+                    "u8" => (value as u8).to_string(),
+                    "u16" => (value as u16).to_string(),
+                    "u32" => (value as u32).to_string(),
+                    _ => value.to_string(),
+                };
+                item.push_str(&format!(
+                    "    pub const {value_name}: {name} = {name}({value});\n"
+                ));
+            }
+            item.push_str("}\n\n");
+        }
+        self.out.push_str(&item);
+        Ok(())
+    }
+
+    /// Getter and setter for a bitfield stored at `bit_offset` within `storage`.
+    fn bitfield_accessors(
+        &self,
+        storage: &str,
+        name: &str,
+        type_id: u32,
+        bit_offset: u32,
+        bit_width: u32,
+    ) -> Result<String> {
+        let mut resolved_id = type_id;
+        let mut resolved = self.btf.get_type(resolved_id)?;
+        while matches!(
+            resolved.kind,
+            BtfKind::Typedef
+                | BtfKind::Const
+                | BtfKind::Volatile
+                | BtfKind::Restrict
+                | BtfKind::TypeTag
+        ) {
+            resolved_id = resolved.size_or_type;
+            resolved = self.btf.get_type(resolved_id)?;
+        }
+        let (value_type, signed, is_enum) = match (&resolved.kind, &resolved.detail) {
+            (BtfKind::Int, BtfTypeDetail::Int(int)) => (
+                self.rust_type(type_id)?,
+                int.encoding & BTF_INT_SIGNED != 0,
+                false,
+            ),
+            // a type alias cannot construct the newtype, so name the enum itself
+            (BtfKind::Enum | BtfKind::Enum64, _) => (
+                self.rust_type(resolved_id)?,
+                resolved.is_signed_enum(),
+                true,
+            ),
+            (kind, _) => {
+                return Err(Error::Parse(format!(
+                    "Unsupported bitfield of BTF kind {kind:?}"
+                )));
+            }
+        };
+        let getter = escape(name);
+        let setter = format!("set_{}", name);
+        let raw = format!("__bitfield_get(&self.{storage}, {bit_offset}, {bit_width})");
+        let raw = if signed {
+            let shift = 64 - bit_width;
+            format!("(({raw} << {shift}) as i64 >> {shift})")
+        } else {
+            raw
+        };
+        let (get, set) = if is_enum {
+            let repr = Self::enum_repr(resolved)?;
+            (
+                format!("{value_type}({raw} as {repr})"),
+                "value.0 as u64".to_string(),
+            )
+        } else {
+            (format!("{raw} as {value_type}"), "value as u64".to_string())
+        };
+        Ok(format!(
+            "    pub fn {getter}(&self) -> {value_type} {{
+        {get}
+    }}
+
+    pub fn {setter}(&mut self, value: {value_type}) {{
+        __bitfield_set(&mut self.{storage}, {bit_offset}, {bit_width}, {set});
+    }}
+"
+        ))
+    }
+
+    fn emit_record(&mut self, type_id: u32) -> Result<()> {
+        let btf_type = self.btf.get_type(type_id)?;
+        let BtfTypeDetail::Struct(members) = &btf_type.detail else {
+            return Err(Error::Parse(format!(
+                "BTF type {type_id} is not a struct or union"
+            )));
+        };
+        let name = self.type_names[&type_id].clone();
+        let is_struct = btf_type.kind == BtfKind::Struct;
+        let size = btf_type.size_or_type;
+        let kind_flag = btf_type.kind_flag;
+
+        let mut fields: Vec<(String, String)> = Vec::new();
+        let mut offsets: Vec<(String, u32)> = Vec::new();
+        let mut accessors = Vec::new();
+        let mut pad_count = 0;
+        let mut anon_count = 0;
+        let mut bitfield_count = 0;
+        // byte offset up to which the struct has been laid out
+        let mut cur = 0;
+        let mut i = 0;
+        while i < members.len() {
+            let member = &members[i];
+            let bit_offset = member.get_offset(kind_flag);
+            if is_struct && bit_offset / 8 > cur {
+                pad_count += 1;
+                fields.push((
+                    format!("__pad_{pad_count}"),
+                    format!("[u8; {}]", bit_offset / 8 - cur),
+                ));
+                cur = bit_offset / 8;
+            }
+            if member.get_bitfield_size(kind_flag) == 0 {
+                let member_name = self.btf.name_by_offset(member.name_off)?;
+                let field_name = if member_name.is_empty() {
+                    anon_count += 1;
+                    format!("__anon_{anon_count}")
+                } else {
+                    escape(member_name)
+                };
+                fields.push((field_name.clone(), self.rust_type(member.type_id)?));
+                if is_struct {
+                    offsets.push((field_name, bit_offset / 8));
+                    cur = bit_offset / 8 + self.btf.size_of(member.type_id)?;
+                }
+                i += 1;
+                continue;
+            }
+            // consecutive bitfields share one byte array; in a union each gets its own
+            let start = bit_offset / 8;
+            let mut end_bit = bit_offset;
+            let mut run = Vec::new();
+            while let Some(member) = members.get(i) {
+                let bitfield_size = member.get_bitfield_size(kind_flag);
+                if bitfield_size == 0 || (!is_struct && !run.is_empty()) {
+                    break;
+                }
+                end_bit = end_bit.max(member.get_offset(kind_flag) + bitfield_size);
+                run.push(member);
+                i += 1;
+            }
+            bitfield_count += 1;
+            let storage = format!("__bitfield_{bitfield_count}");
+            for member in run {
+                let member_name = self.btf.name_by_offset(member.name_off)?;
+                if member_name.is_empty() {
+                    continue;
+                }
+                accessors.push(self.bitfield_accessors(
+                    &storage,
+                    member_name,
+                    member.type_id,
+                    member.get_offset(kind_flag) - start * 8,
+                    member.get_bitfield_size(kind_flag),
+                )?);
+            }
+            let storage_size = end_bit.div_ceil(8) - start;
+            fields.push((storage, format!("[u8; {storage_size}]")));
+            if is_struct {
+                cur = start + storage_size;
+            }
+        }
+        let laid_out = if is_struct {
+            cur
+        } else {
+            let mut max_size = 0;
+            for member in members {
+                let member_size = match member.get_bitfield_size(kind_flag) {
+                    0 => self.btf.size_of(member.type_id)?,
+                    bits => bits.div_ceil(8),
+                };
+                max_size = max_size.max(member_size);
+            }
+            max_size
+        };
+        if size > laid_out {
+            pad_count += 1;
+            let pad_size = if is_struct { size - cur } else { size };
+            fields.push((format!("__pad_{pad_count}"), format!("[u8; {pad_size}]")));
+        }
+
+        let keyword = if is_struct { "struct" } else { "union" };
+        let repr = if self.btf.is_packed(btf_type)? {
+            "C, packed"
+        } else {
+            "C"
+        };
+        let derive = if is_struct {
+            "Debug, Clone, Copy"
+        } else {
+            "Clone, Copy"
+        };
+        let mut item =
+            format!("{ALLOW}\n#[repr({repr})]\n#[derive({derive})]\npub {keyword} {name} {{\n");
+        for (field, field_type) in &fields {
+            item.push_str(&format!("    pub {field}: {field_type},\n"));
+        }
+        item.push_str("}\n\n");
+        item.push_str(&format!(
+            "impl Default for {name} {{\n    fn default() -> Self {{\n        \
+             // SAFETY: every field is plain old data, for which all zeroes is valid\n        \
+             unsafe {{ ::core::mem::zeroed() }}\n    }}\n}}\n\n"
+        ));
+        if !is_struct {
+            // the active field is unknown, so unions cannot print their contents
+            item.push_str(&format!(
+                "impl ::core::fmt::Debug for {name} {{\n    \
+                 fn fmt(&self, f: &mut ::core::fmt::Formatter<'_>) -> ::core::fmt::Result {{\n        \
+                 f.debug_struct(\"{name}\").finish_non_exhaustive()\n    }}\n}}\n\n"
+            ));
+        }
+        if !accessors.is_empty() {
+            self.uses_bitfields = true;
+            let accessors = accessors.join("\n");
+            item.push_str(&format!("{ALLOW}\nimpl {name} {{\n{accessors}}}\n\n"));
+        }
+        item.push_str(&format!(
+            "const _: () = {{\n    assert!(::core::mem::size_of::<{name}>() == {size});\n"
+        ));
+        for (field, offset) in offsets {
+            item.push_str(&format!(
+                "    assert!(::core::mem::offset_of!({name}, {field}) == {offset});\n"
+            ));
+        }
+        item.push_str("};\n\n");
+        self.out.push_str(&item);
+        Ok(())
+    }
+}
+
+/// Generates Rust definitions for the structs, unions, enums and typedefs named in
+/// `type_names` and for every type they contain by value. An empty list generates every named
+/// type in `btf`. Each struct and union is followed by compile-time assertions that its size
+/// and field offsets match the BTF.
+pub fn dump_rust(btf: &Btf, type_names: &[&str]) -> Result<String> {
+    let is_root_kind = |btf_type: &BtfType| {
+        matches!(
+            btf_type.kind,
+            BtfKind::Struct | BtfKind::Union | BtfKind::Enum | BtfKind::Enum64 | BtfKind::Typedef
+        )
+    };
+    let mut roots = Vec::new();
+    if type_names.is_empty() {
+        for (type_id, btf_type) in btf.types() {
+            if is_root_kind(btf_type) && btf_type.name_off != 0 {
+                roots.push(type_id);
+            }
+        }
+    } else {
+        for name in type_names {
+            let type_ids: Vec<u32> = btf
+                .find_by_name(name)
+                .into_iter()
+                .filter(|&type_id| btf.type_by_id(type_id).is_some_and(is_root_kind))
+                .collect();
+            if type_ids.is_empty() {
+                return Err(Error::TypeNotFound(name.to_string()));
+            }
+            roots.extend(type_ids);
+        }
+    }
+
+    let mut dumper = RustDumper::new(btf);
+    for type_id in roots {
+        dumper.collect_root(type_id)?;
+    }
+    for type_id in dumper.items.clone() {
+        match dumper.btf.get_type(type_id)?.kind {
+            BtfKind::Typedef => dumper.emit_typedef(type_id)?,
+            BtfKind::Enum | BtfKind::Enum64 => dumper.emit_enum(type_id)?,
+            _ => dumper.emit_record(type_id)?,
+        }
+    }
+    if dumper.uses_bitfields {
+        dumper.out.insert_str(0, BITFIELD_HELPERS);
+    }
+    Ok(dumper.out)
+}
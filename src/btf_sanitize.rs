@@ -0,0 +1,270 @@
+use crate::{
+    btf::{Btf, BtfKind, BtfLinkage, BtfMember, BtfTypeDetail, BtfVarSecinfo, BTF_INT_SIGNED},
+    btf_builder,
+    error::{Context as _, Result},
+    syscalls_wrapper,
+};
+
+/// BTF kinds and encodings that were added to the kernel over time. A `false` field makes
+/// [`sanitize_btf`] rewrite the corresponding types into ones older kernels accept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BtfFeatures {
+    /// `FUNC` and `FUNC_PROTO` (Linux 5.0).
+    pub func: bool,
+    /// `FUNC` with global linkage (5.6).
+    pub func_global: bool,
+    /// `VAR` and `DATASEC` (5.2).
+    pub datasec: bool,
+    /// `FLOAT` (5.13).
+    pub float: bool,
+    /// `DECL_TAG` (5.16).
+    pub decl_tag: bool,
+    /// `TYPE_TAG` (5.17).
+    pub type_tag: bool,
+    /// `ENUM64` and signed enums (6.0).
+    pub enum64: bool,
+}
+
+impl Default for BtfFeatures {
+    /// Everything supported, so nothing is rewritten.
+    fn default() -> Self {
+        BtfFeatures {
+            func: true,
+            func_global: true,
+            datasec: true,
+            float: true,
+            decl_tag: true,
+            type_tag: true,
+            enum64: true,
+        }
+    }
+}
+
+const BTF_TYPE_LEN: usize = 12;
+
+fn type_info(kind: BtfKind, kind_flag: bool, vlen: u32) -> u32 {
+    ((kind_flag as u32) << 31) | ((kind as u32) << 24) | vlen
+}
+
+/// `int`, as type id 1 of every probe.
+const PROBE_INT: [u32; 4] = [
+    0,
+    (BtfKind::Int as u32) << 24,
+    4,
+    (BTF_INT_SIGNED as u32) << 24 | 32,
+];
+
+/// Whether the kernel accepts a BTF blob made of `types` and `strings`.
+fn probe_btf(types: &[u32], strings: &[u8]) -> bool {
+    let types = types
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .collect::<Vec<_>>();
+    match unsafe {
+        syscalls_wrapper::bpf_btf_load(
+            &btf_builder::encode_sections(&types, strings),
+            &mut Vec::new(),
+            0,
+        )
+    } {
+        Ok(fd) => {
+            let _ = unsafe { syscalls_wrapper::close(fd) };
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+impl BtfFeatures {
+    /// Detects what the running kernel supports by loading a minimal BTF blob per feature, in
+    /// the same way as libbpf.
+    pub fn probe() -> BtfFeatures {
+        let with_int = |types: &[u32]| [&PROBE_INT[..], types].concat();
+        BtfFeatures {
+            func: probe_btf(
+                &with_int(&[
+                    0,
+                    type_info(BtfKind::FuncProto, false, 0),
+                    0,
+                    1,
+                    type_info(BtfKind::Func, false, BtfLinkage::Static as u32),
+                    2,
+                ]),
+                b"\0x\0",
+            ),
+            func_global: probe_btf(
+                &with_int(&[
+                    0,
+                    type_info(BtfKind::FuncProto, false, 0),
+                    0,
+                    1,
+                    type_info(BtfKind::Func, false, BtfLinkage::Global as u32),
+                    2,
+                ]),
+                b"\0x\0",
+            ),
+            datasec: probe_btf(
+                &with_int(&[
+                    1,
+                    type_info(BtfKind::Var, false, 0),
+                    1,
+                    BtfLinkage::Static as u32,
+                    3,
+                    type_info(BtfKind::DataSec, false, 1),
+                    4,
+                    2,
+                    0,
+                    4,
+                ]),
+                b"\0x\0val\0",
+            ),
+            float: probe_btf(&[1, type_info(BtfKind::Float, false, 0), 4], b"\0float\0"),
+            decl_tag: probe_btf(
+                &with_int(&[
+                    1,
+                    type_info(BtfKind::Var, false, 0),
+                    1,
+                    BtfLinkage::Global as u32,
+                    1,
+                    type_info(BtfKind::DeclTag, false, 0),
+                    2,
+                    -1i32 as u32,
+                ]),
+                b"\0tag\0",
+            ),
+            type_tag: probe_btf(
+                &with_int(&[
+                    1,
+                    type_info(BtfKind::TypeTag, false, 0),
+                    1,
+                    0,
+                    type_info(BtfKind::Ptr, false, 0),
+                    2,
+                ]),
+                b"\0tag\0",
+            ),
+            enum64: probe_btf(&[1, type_info(BtfKind::Enum64, false, 0), 8], b"\0enum64\0"),
+        }
+    }
+}
+
+fn write_u32(data: &mut [u8], pos: usize, value: u32) -> Result<()> {
+    data.get_mut(pos..pos + 4)
+        .context("BTF type out of bounds")?
+        .copy_from_slice(&value.to_le_bytes());
+    Ok(())
+}
+
+/// Turns the type at `pos`, which must carry 4 bytes of extra data, into a 1-byte `INT`.
+fn write_int_placeholder(data: &mut [u8], pos: usize) -> Result<()> {
+    write_u32(data, pos + 4, type_info(BtfKind::Int, false, 0))?;
+    write_u32(data, pos + 8, 1)?;
+    write_u32(data, pos + BTF_TYPE_LEN, 8)
+}
+
+/// Rewrites the types of `raw`, an encoding of `btf`, that the kernel described by `features`
+/// would reject, following libbpf's substitutions:
+///
+/// - `VAR` and `DECL_TAG` become 1-byte `INT`s and `DATASEC` a `STRUCT` of its variables.
+/// - `FUNC_PROTO` becomes an `ENUM` of its parameters and `FUNC` a `TYPEDEF`.
+/// - Global functions become static ones.
+/// - `FLOAT` becomes an empty `STRUCT` of the same size and `TYPE_TAG` a `CONST`.
+/// - `ENUM64` becomes a `UNION` of placeholder members, and enums lose their signedness.
+///
+/// Types keep their encoded size and id, so func info and map type ids stay valid; only
+/// `ENUM64` needs an extra type, which is appended. `raw` may differ from the data `btf` was
+/// parsed from in values (e.g. after DATASEC fixups) but not in layout.
+pub fn sanitize_btf(btf: &Btf, raw: &[u8], features: &BtfFeatures) -> Result<Vec<u8>> {
+    let mut data = raw.to_vec();
+    let str_start = (btf.header.hdr_len + btf.header.str_off) as usize;
+    let mut pos = (btf.header.hdr_len + btf.header.type_off) as usize;
+    let enum64_placeholder_id = btf.type_count();
+    let mut needs_enum64_placeholder = false;
+    // skip the void type that the parser prepends
+    for (_, btf_type) in btf.types().skip(1) {
+        let vlen = btf_type.vlen as u32;
+        let extra = pos + BTF_TYPE_LEN;
+        match (btf_type.kind, &btf_type.detail) {
+            (BtfKind::Var, _) if !features.datasec => write_int_placeholder(&mut data, pos)?,
+            (BtfKind::DeclTag, _) if !features.decl_tag => write_int_placeholder(&mut data, pos)?,
+            (BtfKind::DataSec, BtfTypeDetail::DataSec(vars)) if !features.datasec => {
+                // section names such as `.data` are not valid struct names
+                let name_start = str_start + btf_type.name_off as usize;
+                let name_len = btf.name_of(btf_type)?.len();
+                for c in &mut data[name_start..name_start + name_len] {
+                    if *c == b'.' || *c == b'?' {
+                        *c = b'_';
+                    }
+                }
+                write_u32(&mut data, pos + 4, type_info(BtfKind::Struct, false, vlen))?;
+                for (idx, var_secinfo) in vars.iter().enumerate() {
+                    let member = extra + idx * size_of::<BtfVarSecinfo>();
+                    let var = btf.get_type(var_secinfo.type_id)?;
+                    write_u32(&mut data, member, var.name_off)?;
+                    write_u32(&mut data, member + 4, var_secinfo.type_id)?;
+                    write_u32(&mut data, member + 8, var_secinfo.offset * 8)?;
+                }
+            }
+            (BtfKind::FuncProto, _) if !features.func => {
+                write_u32(&mut data, pos + 4, type_info(BtfKind::Enum, false, vlen))?;
+                write_u32(&mut data, pos + 8, 4)?;
+            }
+            (BtfKind::Func, _) if !features.func => {
+                write_u32(&mut data, pos + 4, type_info(BtfKind::Typedef, false, 0))?;
+            }
+            (BtfKind::Func, BtfTypeDetail::Func(linkage))
+                if (*linkage == BtfLinkage::Global && !features.func_global)
+                    // no kernel accepts extern functions in program BTF
+                    || *linkage == BtfLinkage::Extern =>
+            {
+                write_u32(&mut data, pos + 4, type_info(BtfKind::Func, false, 0))?;
+            }
+            (BtfKind::Float, _) if !features.float => {
+                write_u32(&mut data, pos, 0)?;
+                write_u32(&mut data, pos + 4, type_info(BtfKind::Struct, false, 0))?;
+            }
+            (BtfKind::TypeTag, _) if !features.type_tag => {
+                write_u32(&mut data, pos, 0)?;
+                write_u32(&mut data, pos + 4, type_info(BtfKind::Const, false, 0))?;
+            }
+            (BtfKind::Enum, _) if btf_type.kind_flag && !features.enum64 => {
+                write_u32(&mut data, pos + 4, type_info(BtfKind::Enum, false, vlen))?;
+            }
+            (BtfKind::Enum64, _) if !features.enum64 => {
+                write_u32(&mut data, pos + 4, type_info(BtfKind::Union, false, vlen))?;
+                for idx in 0..vlen as usize {
+                    let member = extra + idx * size_of::<BtfMember>();
+                    write_u32(&mut data, member + 4, enum64_placeholder_id)?;
+                    write_u32(&mut data, member + 8, 0)?;
+                }
+                needs_enum64_placeholder = true;
+            }
+            _ => {}
+        }
+        pos += btf_type.encoded_len();
+    }
+    if !needs_enum64_placeholder {
+        return Ok(data);
+    }
+
+    // append `enum64_placeholder`, a 1-byte int, as the type of the union members
+    let type_start = (btf.header.hdr_len + btf.header.type_off) as usize;
+    let mut types = data
+        .get(type_start..type_start + btf.header.type_len as usize)
+        .context("Type section out of bounds")?
+        .to_vec();
+    let mut strings = data
+        .get(str_start..str_start + btf.header.str_len as usize)
+        .context("String section out of bounds")?
+        .to_vec();
+    for word in [
+        strings.len() as u32,
+        type_info(BtfKind::Int, false, 0),
+        1,
+        8,
+    ] {
+        types.extend_from_slice(&word.to_le_bytes());
+    }
+    strings.extend_from_slice(b"enum64_placeholder\0");
+    Ok(btf_builder::encode_sections(&types, &strings))
+}
@@ -0,0 +1,119 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use crate::{
+    elf_parser,
+    error::{Error, Result},
+    syscalls_wrapper,
+};
+
+const KERNEL_BTF_DIR: &str = "/sys/kernel/btf";
+
+/// Where the loader finds the kernel's BTF for CO-RE relocations.
+#[derive(Debug, Clone, Default)]
+pub enum BtfSource {
+    /// `/sys/kernel/btf/vmlinux` together with the split BTF of the loaded modules.
+    #[default]
+    Sysfs,
+    /// A raw BTF file, e.g. one shipped from BTFHub.
+    File(PathBuf),
+    /// A directory of raw BTF files named after kernel releases (`<uname -r>.btf`).
+    Directory(PathBuf),
+    /// An ELF file carrying a `.BTF` section, such as an uncompressed vmlinux image.
+    Elf(PathBuf),
+    /// Tries each source in order and uses the first one that can be read.
+    SearchPath(Vec<BtfSource>),
+}
+
+/// Raw kernel BTF read from a [`BtfSource`]. Module BTF is split BTF on top of `vmlinux` and
+/// is only read on the first call to [`KernelBtf::modules`].
+#[derive(Debug, Clone)]
+pub struct KernelBtf {
+    pub vmlinux: Vec<u8>,
+    pub module_paths: Vec<PathBuf>,
+    modules: OnceLock<Vec<Vec<u8>>>,
+}
+
+impl KernelBtf {
+    fn new(vmlinux: Vec<u8>, module_paths: Vec<PathBuf>) -> KernelBtf {
+        KernelBtf {
+            vmlinux,
+            module_paths,
+            modules: OnceLock::new(),
+        }
+    }
+
+    /// The split BTF of every module in `module_paths`.
+    pub fn modules(&self) -> Result<&[Vec<u8>]> {
+        if let Some(modules) = self.modules.get() {
+            return Ok(modules);
+        }
+        let mut modules = Vec::new();
+        for path in &self.module_paths {
+            modules.push(std::fs::read(path)?);
+        }
+        Ok(self.modules.get_or_init(|| modules))
+    }
+}
+
+impl BtfSource {
+    /// The locations libbpf falls back to when sysfs has no BTF, after sysfs itself.
+    pub fn default_search_path() -> Result<BtfSource> {
+        let release = syscalls_wrapper::uname_release()?;
+        let mut sources = vec![BtfSource::Sysfs];
+        for path in [
+            format!("/boot/vmlinux-{release}"),
+            format!("/lib/modules/{release}/vmlinux-{release}"),
+            format!("/lib/modules/{release}/build/vmlinux"),
+            format!("/usr/lib/modules/{release}/kernel/vmlinux"),
+            format!("/usr/lib/debug/boot/vmlinux-{release}"),
+            format!("/usr/lib/debug/boot/vmlinux-{release}.debug"),
+            format!("/usr/lib/debug/lib/modules/{release}/vmlinux"),
+        ] {
+            sources.push(BtfSource::Elf(path.into()));
+        }
+        Ok(BtfSource::SearchPath(sources))
+    }
+
+    pub fn read(&self) -> Result<KernelBtf> {
+        match self {
+            BtfSource::Sysfs => {
+                let vmlinux = std::fs::read(Path::new(KERNEL_BTF_DIR).join("vmlinux"))?;
+                let mut paths = Vec::new();
+                for entry in std::fs::read_dir(KERNEL_BTF_DIR)? {
+                    let entry = entry?;
+                    if entry.file_name() != "vmlinux" {
+                        paths.push(entry.path());
+                    }
+                }
+                paths.sort();
+                Ok(KernelBtf::new(vmlinux, paths))
+            }
+            BtfSource::File(path) => Ok(KernelBtf::new(std::fs::read(path)?, Vec::new())),
+            BtfSource::Directory(dir) => {
+                let release = syscalls_wrapper::uname_release()?;
+                BtfSource::File(dir.join(format!("{release}.btf"))).read()
+            }
+            BtfSource::Elf(path) => {
+                let elf = elf_parser::parse_elf(path)?;
+                let btf = elf
+                    .get_section_body(".BTF")
+                    .ok_or_else(|| Error::SectionMissing(".BTF".to_string()))?;
+                Ok(KernelBtf::new(btf.to_vec(), Vec::new()))
+            }
+            BtfSource::SearchPath(sources) => {
+                let mut last_error = None;
+                for source in sources {
+                    match source.read() {
+                        Ok(kernel_btf) => return Ok(kernel_btf),
+                        Err(e) => last_error = Some(e),
+                    }
+                }
+                Err(last_error
+                    .unwrap_or_else(|| Error::InvalidArgument("Empty BTF search path".to_string())))
+            }
+        }
+    }
+}
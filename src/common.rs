@@ -1,4 +1,4 @@
-use anyhow::{bail, Context as _, Result};
+use crate::error::{Error, Result};
 
 pub fn read_struct<T>(data: &[u8], offset: usize) -> Option<&T> {
     if offset + size_of::<T>() > data.len() {
@@ -16,7 +16,9 @@ pub fn read_struct<T>(data: &[u8], offset: usize) -> Option<&T> {
 }
 
 pub fn get_name_from_string_section(string_encoding: &[u8], offset: usize) -> Result<&str> {
-    let end = string_encoding[offset..]
+    let end = string_encoding
+        .get(offset..)
+        .ok_or_else(|| Error::Parse(format!("String offset {offset} out of bounds")))?
         .iter()
         .position(|&c| c == 0)
         .map(|pos| offset + pos)
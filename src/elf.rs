@@ -1,11 +1,14 @@
-use anyhow::{bail, Context as _, Result};
-use std::collections::HashMap;
+use crate::error::{Context as _, Error, Result};
+use std::{collections::HashMap, sync::OnceLock};
 
 use crate::{
     btf::{
         BpfCoreRelo, BpfCoreReloKind, Btf, BtfExt, BtfExtInfoSec, BtfKind, BtfType, BtfTypeDetail,
+        BTF_INT_SIGNED,
     },
-    common, elf_parser,
+    btf_parser,
+    btf_source::KernelBtf,
+    common,
 };
 
 #[repr(C)]
@@ -42,12 +45,88 @@ pub struct Elf64Shdr {
     pub sh_entsize: u64,
 }
 
+pub const SHT_PROGBITS: u32 = 1;
+pub const SHT_SYMTAB: u32 = 2;
+pub const SHT_NOBITS: u32 = 8;
+pub const SHF_EXECINSTR: u64 = 0x4;
+
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct Elf64Sym {
+    pub st_name: u32,
+    pub st_info: u8,
+    pub st_other: u8,
+    pub st_shndx: u16,
+    pub st_value: u64,
+    pub st_size: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolBinding {
+    Local,
+    Global,
+    Weak,
+    /// `STB_GNU_UNIQUE` and the OS and processor specific bindings.
+    Other(u8),
+}
+
+impl From<u8> for SymbolBinding {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => SymbolBinding::Local,
+            1 => SymbolBinding::Global,
+            2 => SymbolBinding::Weak,
+            _ => SymbolBinding::Other(value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolType {
+    NoType,
+    Object,
+    Func,
+    Section,
+    File,
+    /// `STT_COMMON`, `STT_TLS`, `STT_GNU_IFUNC` and the OS and processor specific types.
+    Other(u8),
+}
+
+impl From<u8> for SymbolType {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => SymbolType::NoType,
+            1 => SymbolType::Object,
+            2 => SymbolType::Func,
+            3 => SymbolType::Section,
+            4 => SymbolType::File,
+            _ => SymbolType::Other(value),
+        }
+    }
+}
+
+/// Section index of symbols that are not defined in this object (e.g. `extern` declarations).
+pub const SHN_UNDEF: u16 = 0;
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub section_index: u16,
+    pub value: u64,
+    pub size: u64,
+    pub binding: SymbolBinding,
+    pub sym_type: SymbolType,
+}
+
 #[derive(Debug, Clone)]
 pub struct Elf {
     pub data: Vec<u8>,
     pub ehdr: Elf64Ehdr,
     pub section_name_table: Option<Vec<u8>>,
     pub shdrs: HashMap<String, Elf64Shdr>,
+    /// Section names in section header order, so that `st_shndx` can be mapped to a name.
+    pub section_names: Vec<String>,
+    pub symbols: Vec<Symbol>,
 }
 
 #[repr(C)]
@@ -68,7 +147,41 @@ pub struct Elf64Rel {
     pub sym_idx: u32,
 }
 
-pub fn relocate(data: &mut [u8], rel_section: &[Elf64Rel], rel_map: &HashMap<u32, i64>) {
+/// `src_reg` values of `BPF_LD_IMM64` telling the kernel how to interpret the immediate.
+pub const BPF_PSEUDO_MAP_FD: u8 = 1;
+pub const BPF_PSEUDO_MAP_VALUE: u8 = 2;
+pub const BPF_PSEUDO_FUNC: u8 = 4;
+/// `src_reg` of a `BPF_CALL` whose immediate is a relative offset to a subprogram.
+pub const BPF_PSEUDO_CALL: u8 = 1;
+
+pub(crate) const BPF_INSN_SIZE: usize = 8;
+const BPF_LD_IMM64: u8 = 0x18;
+const BPF_JMP_CALL: u8 = 0x85;
+
+/// Instruction classes (the low three bits of the opcode).
+const BPF_CLASS_MASK: u8 = 0x07;
+const BPF_CLASS_LDX: u8 = 0x01;
+const BPF_CLASS_ST: u8 = 0x02;
+const BPF_CLASS_STX: u8 = 0x03;
+const BPF_CLASS_ALU: u8 = 0x04;
+const BPF_CLASS_ALU64: u8 = 0x07;
+/// Source operand bit of ALU and jump instructions; clear for an immediate operand.
+const BPF_SRC_X: u8 = 0x08;
+
+#[derive(Debug, Clone, Copy)]
+pub enum RelocationTarget {
+    /// Load the map itself (`&map`).
+    MapFd(i32),
+    /// Load a pointer into the value of the map's first entry, used for global variables.
+    MapValue { fd: i32, offset: u32 },
+}
+
+pub fn relocate(
+    elf: &Elf,
+    data: &mut [u8],
+    rel_section: &[Elf64Rel],
+    rel_map: &HashMap<u32, RelocationTarget>,
+) -> Result<()> {
     for Elf64Rel {
         r_offset,
         rel_type,
@@ -78,59 +191,981 @@ pub fn relocate(data: &mut [u8], rel_section: &[Elf64Rel], rel_map: &HashMap<u32
         match rel_type {
             BpfRelocationType::RBpfNone => {}
             BpfRelocationType::RBpf64_64 => {
-                if let Some(&addr) = rel_map.get(sym_idx) {
-                    let r_offset = *r_offset as usize;
-                    let offset = r_offset + 4;
-                    // rewrite src to 0x1
-                    if r_offset + 1 < data.len() {
-                        let value = data[r_offset + 1];
-                        let new_value = value | 0x10;
-                        data[r_offset + 1] = new_value;
+                let Some(&target) = rel_map.get(sym_idx) else {
+                    let sym = elf
+                        .symbol(*sym_idx)
+                        .context("Invalid relocation symbol index")?;
+                    let section_name = elf
+                        .section_name(sym.section_index)
+                        .filter(|_| sym.section_index != SHN_UNDEF);
+                    // references to code are subprogram addresses, patched when linking
+                    if section_name.is_some_and(|name| elf.is_executable_section(name)) {
+                        continue;
                     }
-                    // rewrite imm to map_fd
-                    if offset + 4 <= data.len() {
-                        let value =
-                            u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
-                        let new_value = value.wrapping_add(addr as u32);
-                        data[offset..offset + 4].copy_from_slice(&new_value.to_le_bytes());
+                    let name = match (sym.sym_type, section_name) {
+                        (SymbolType::Section, Some(section_name)) => section_name,
+                        _ => &sym.name,
+                    };
+                    return Err(Error::SymbolMissing(name.to_string()));
+                };
+                let r_offset = *r_offset as usize;
+                // ld_imm64 spans two instructions
+                if r_offset + 16 > data.len() {
+                    return Err(Error::Parse(format!(
+                        "Relocation offset {r_offset} out of bounds"
+                    )));
+                }
+                let (src, fd, value_offset) = match target {
+                    RelocationTarget::MapFd(fd) => (BPF_PSEUDO_MAP_FD, fd, None),
+                    RelocationTarget::MapValue { fd, offset } => {
+                        (BPF_PSEUDO_MAP_VALUE, fd, Some(offset))
                     }
+                };
+                // rewrite src
+                data[r_offset + 1] = (data[r_offset + 1] & 0x0f) | (src << 4);
+                // rewrite imm to map_fd; for section symbols the addend is stored in imm
+                let offset = r_offset + 4;
+                let addend = u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+                data[offset..offset + 4].copy_from_slice(&(fd as u32).to_le_bytes());
+                if let Some(value_offset) = value_offset {
+                    let offset = r_offset + 12;
+                    let new_value = addend.wrapping_add(value_offset);
+                    data[offset..offset + 4].copy_from_slice(&new_value.to_le_bytes());
+                }
+            }
+            // call relocations are resolved when subprograms are linked into the program
+            BpfRelocationType::RBpf64_32 => {}
+            _ => {
+                return Err(Error::RelocationUnsupported(format!(
+                    "{rel_type:?} at offset {r_offset}"
+                )))
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A function copied into a linked program: `size` bytes from `section_offset` of
+/// `section_name`, placed at byte offset `insn_offset` of the program.
+#[derive(Debug, Clone)]
+pub struct LinkSegment<'a> {
+    pub section_name: &'a str,
+    pub section_offset: usize,
+    pub size: usize,
+    pub insn_offset: usize,
+}
+
+/// Strips a `___flavor` suffix. Flavors let a program carry several definitions of the same
+/// kernel type (e.g. `struct task_struct___v5_10`) that all relocate against `task_struct`.
+fn essential_name(name: &str) -> &str {
+    let bytes = name.as_bytes();
+    for i in (0..bytes.len().saturating_sub(4)).rev() {
+        if bytes[i] != b'_' && &bytes[i + 1..i + 4] == b"___" && bytes[i + 4] != b'_' {
+            return &name[..=i];
+        }
+    }
+    name
+}
+
+/// Enum and enum64 describe the same C type and may be swapped between BTF versions.
+fn core_kinds_compatible(local: BtfKind, target: BtfKind) -> bool {
+    local == target
+        || matches!(
+            (local, target),
+            (
+                BtfKind::Enum | BtfKind::Enum64,
+                BtfKind::Enum | BtfKind::Enum64
+            )
+        )
+}
+
+/// Returns the ids of all target types named after the essential name of `local_type` and of a
+/// compatible kind. Kernel BTF carries no flavors, so target names are matched as they are.
+fn find_core_candidates(
+    local_btf: &Btf,
+    local_type: &BtfType,
+    target_btf: &Btf,
+) -> Result<Vec<u32>> {
+    let name = essential_name(local_btf.name_of(local_type)?);
+    if name.is_empty() {
+        return Err(Error::RelocationUnsupported(
+            "CO-RE relocation against an anonymous type".to_string(),
+        ));
+    }
+    let mut candidates = Vec::new();
+    for type_id in target_btf.find_by_name(name) {
+        let target_type = target_btf.get_type(type_id)?;
+        if core_kinds_compatible(local_type.kind, target_type.kind) {
+            candidates.push(type_id);
+        }
+    }
+    Ok(candidates)
+}
+
+/// Returns the enumerators of an enum or enum64 type with their values sign- or zero-extended
+/// to 64 bits.
+fn enum_values<'a>(btf: &'a Btf<'a>, enum_type: &BtfType) -> Result<Vec<(&'a str, u64)>> {
+    let name = |name_off: u32| btf.name_by_offset(name_off);
+    match &enum_type.detail {
+        BtfTypeDetail::Enum(values) => values
+            .iter()
+            .map(|value| {
+                let extended = if enum_type.is_signed_enum() {
+                    value.val as i64 as u64
+                } else {
+                    value.val as u32 as u64
+                };
+                Ok((name(value.name_off)?, extended))
+            })
+            .collect(),
+        BtfTypeDetail::Enum64(values) => values
+            .iter()
+            .map(|value| Ok((name(value.name_off)?, value.get_value())))
+            .collect(),
+        _ => Err(Error::RelocationUnsupported(
+            "Enum value relocation on a non-enum type".to_string(),
+        )),
+    }
+}
+
+const CORE_MAX_DEPTH: u32 = 32;
+
+/// Loose compatibility used by the type-based relocations, following libbpf's
+/// `bpf_core_types_are_compat`: modifiers and typedefs are ignored, named composite types only
+/// need to agree on their kind, and pointers, arrays and function prototypes are compared
+/// recursively.
+fn core_types_are_compat(
+    local_btf: &Btf,
+    local_id: u32,
+    target_btf: &Btf,
+    target_id: u32,
+    depth: u32,
+) -> Result<bool> {
+    if depth == 0 {
+        return Err(Error::Parse("BTF type nesting is too deep".to_string()));
+    }
+    if local_id == 0 || target_id == 0 {
+        return Ok(local_id == target_id);
+    }
+    let local = local_btf.resolve(local_id)?;
+    let target = target_btf.resolve(target_id)?;
+    if !core_kinds_compatible(local.kind, target.kind) {
+        return Ok(false);
+    }
+    let recurse = |local_id, target_id| {
+        core_types_are_compat(local_btf, local_id, target_btf, target_id, depth - 1)
+    };
+    match (&local.kind, &local.detail, &target.detail) {
+        (
+            BtfKind::Struct | BtfKind::Union | BtfKind::Enum | BtfKind::Enum64 | BtfKind::Fwd,
+            _,
+            _,
+        ) => Ok(true),
+        (BtfKind::Int, BtfTypeDetail::Int(local_int), BtfTypeDetail::Int(target_int)) => {
+            Ok(local_int.offset == 0 && target_int.offset == 0)
+        }
+        (BtfKind::Ptr, _, _) => recurse(local.size_or_type, target.size_or_type),
+        (BtfKind::Array, BtfTypeDetail::Array(local_array), BtfTypeDetail::Array(target_array)) => {
+            recurse(local_array.type_id, target_array.type_id)
+        }
+        (
+            BtfKind::FuncProto,
+            BtfTypeDetail::FuncProto(local_params),
+            BtfTypeDetail::FuncProto(target_params),
+        ) => {
+            if local_params.len() != target_params.len() {
+                return Ok(false);
+            }
+            for (local_param, target_param) in local_params.iter().zip(target_params) {
+                if !recurse(local_param.type_id, target_param.type_id)? {
+                    return Ok(false);
+                }
+            }
+            recurse(local.size_or_type, target.size_or_type)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Strict matching used by `TypeMatches`, following libbpf's `bpf_core_types_match`: names
+/// must agree at every level, every local member and enumerator must exist in the target, and
+/// integers must have the same size and signedness. Composites behind a pointer only need to
+/// agree on their kind.
+fn core_types_match(
+    local_btf: &Btf,
+    local_id: u32,
+    target_btf: &Btf,
+    target_id: u32,
+    behind_ptr: bool,
+    depth: u32,
+) -> Result<bool> {
+    if depth == 0 {
+        return Err(Error::Parse("BTF type nesting is too deep".to_string()));
+    }
+    if local_id == 0 || target_id == 0 {
+        return Ok(local_id == target_id);
+    }
+    let local = local_btf.resolve(local_id)?;
+    let target = target_btf.resolve(target_id)?;
+    if essential_name(local_btf.name_of(local)?) != essential_name(target_btf.name_of(target)?) {
+        return Ok(false);
+    }
+    let recurse = |local_id, target_id, behind_ptr| {
+        core_types_match(
+            local_btf,
+            local_id,
+            target_btf,
+            target_id,
+            behind_ptr,
+            depth - 1,
+        )
+    };
+    // a forward declaration's kind_flag tells whether it declares a union
+    let is_union = |btf_type: &BtfType| match btf_type.kind {
+        BtfKind::Fwd => btf_type.kind_flag,
+        kind => kind == BtfKind::Union,
+    };
+    match (&local.kind, &local.detail, &target.detail) {
+        (BtfKind::Fwd, _, _) => Ok(matches!(
+            target.kind,
+            BtfKind::Fwd | BtfKind::Struct | BtfKind::Union
+        ) && is_union(local) == is_union(target)),
+        (BtfKind::Enum | BtfKind::Enum64, _, _) => {
+            if !matches!(target.kind, BtfKind::Enum | BtfKind::Enum64)
+                || local.size_or_type != target.size_or_type
+            {
+                return Ok(false);
+            }
+            let target_values = enum_values(target_btf, target)?;
+            for (name, _) in enum_values(local_btf, local)? {
+                if !target_values
+                    .iter()
+                    .any(|(target_name, _)| essential_name(target_name) == essential_name(name))
+                {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        }
+        (BtfKind::Struct | BtfKind::Union, BtfTypeDetail::Struct(local_members), _) => {
+            if behind_ptr {
+                return Ok(
+                    matches!(target.kind, BtfKind::Fwd | BtfKind::Struct | BtfKind::Union)
+                        && is_union(local) == is_union(target),
+                );
+            }
+            let BtfTypeDetail::Struct(target_members) = &target.detail else {
+                return Ok(false);
+            };
+            if local.kind != target.kind || local_members.len() > target_members.len() {
+                return Ok(false);
+            }
+            for local_member in local_members {
+                let name = local_btf.name_by_offset(local_member.name_off)?;
+                let mut target_member = None;
+                for member in target_members {
+                    let target_name = target_btf.name_by_offset(member.name_off)?;
+                    if target_name == name {
+                        target_member = Some(member);
+                        break;
+                    }
+                }
+                let Some(target_member) = target_member else {
+                    return Ok(false);
+                };
+                if !recurse(local_member.type_id, target_member.type_id, behind_ptr)? {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        }
+        (BtfKind::Int, BtfTypeDetail::Int(local_int), BtfTypeDetail::Int(target_int)) => {
+            Ok(local.size_or_type == target.size_or_type
+                && local_int.encoding & BTF_INT_SIGNED == target_int.encoding & BTF_INT_SIGNED)
+        }
+        (BtfKind::Ptr, _, _) => {
+            Ok(target.kind == BtfKind::Ptr
+                && recurse(local.size_or_type, target.size_or_type, true)?)
+        }
+        (BtfKind::Array, BtfTypeDetail::Array(local_array), BtfTypeDetail::Array(target_array)) => {
+            Ok(local_array.nelems == target_array.nelems
+                && recurse(local_array.type_id, target_array.type_id, behind_ptr)?)
+        }
+        (
+            BtfKind::FuncProto,
+            BtfTypeDetail::FuncProto(local_params),
+            BtfTypeDetail::FuncProto(target_params),
+        ) => {
+            if local_params.len() != target_params.len() {
+                return Ok(false);
+            }
+            for (local_param, target_param) in local_params.iter().zip(target_params) {
+                if !recurse(local_param.type_id, target_param.type_id, behind_ptr)? {
+                    return Ok(false);
+                }
+            }
+            recurse(local.size_or_type, target.size_or_type, behind_ptr)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// A field reached by a CO-RE access string, with its offset from the start of the root type.
+struct CoreField {
+    type_id: u32,
+    bit_offset: u32,
+    bitfield_size: u32,
+}
+
+/// One step of a local access string below the root type. Anonymous members are not recorded,
+/// as their layout may differ in the target.
+enum CoreAccess<'a> {
+    Member { name: &'a str, type_id: u32 },
+    Index(u32),
+}
+
+/// A parsed access string such as `0:2:0:5`: the root index, the steps below it and the field
+/// they lead to in the local BTF.
+struct CoreSpec<'a> {
+    root_index: u32,
+    accesses: Vec<CoreAccess<'a>>,
+    field: CoreField,
+}
+
+impl CoreSpec<'_> {
+    /// Renders the accessed field as C would, e.g. `ifa_list[2].ifa_address`.
+    fn field_path(&self) -> String {
+        let mut path = String::new();
+        for access in &self.accesses {
+            match access {
+                CoreAccess::Member { name, .. } if path.is_empty() => path.push_str(name),
+                CoreAccess::Member { name, .. } => {
+                    path.push('.');
+                    path.push_str(name);
+                }
+                CoreAccess::Index(index) => path.push_str(&format!("[{index}]")),
+            }
+        }
+        path
+    }
+}
+
+fn parse_access_str(access_str: &str) -> Result<Vec<u32>> {
+    access_str
+        .split(':')
+        .map(|index| index.parse::<u32>().ok())
+        .collect::<Option<Vec<_>>>()
+        .with_context(|| format!("Invalid access string: {access_str}"))
+}
+
+/// Offset of the `index`-th element of an array of `type_id`, as used for the root index and
+/// array accesses.
+fn core_element_bit_offset(btf: &Btf, type_id: u32, index: u32) -> Result<u32> {
+    if index == 0 {
+        return Ok(0);
+    }
+    index
+        .checked_mul(btf.size_of(type_id)?)
+        .and_then(|byte_offset| byte_offset.checked_mul(8))
+        .with_context(|| format!("Array index {index} out of range"))
+}
+
+/// Adds the offset of an access to the offset of its parent.
+fn core_add_bit_offset(bit_offset: u32, access_bit_offset: u32) -> Result<u32> {
+    bit_offset
+        .checked_add(access_bit_offset)
+        .context("Field offset out of range")
+}
+
+/// Resolves a local access string, skipping modifiers and typedefs at every level.
+fn core_parse_spec<'a>(btf: &'a Btf<'a>, type_id: u32, access_str: &str) -> Result<CoreSpec<'a>> {
+    let indices = parse_access_str(access_str)?;
+    let (&root_index, indices) = indices
+        .split_first()
+        .with_context(|| format!("Invalid access string: {access_str}"))?;
+    let mut field = CoreField {
+        type_id,
+        bit_offset: core_element_bit_offset(btf, type_id, root_index)?,
+        bitfield_size: 0,
+    };
+    let mut accesses = Vec::new();
+    for &index in indices {
+        let parent = btf.resolve(field.type_id)?;
+        match &parent.detail {
+            BtfTypeDetail::Struct(members) => {
+                let member = members
+                    .get(index as usize)
+                    .with_context(|| format!("Invalid member index in {access_str}"))?;
+                let name = btf.name_by_offset(member.name_off)?;
+                if !name.is_empty() {
+                    accesses.push(CoreAccess::Member {
+                        name,
+                        type_id: member.type_id,
+                    });
+                }
+                field = CoreField {
+                    type_id: member.type_id,
+                    bit_offset: core_add_bit_offset(
+                        field.bit_offset,
+                        member.get_offset(parent.kind_flag),
+                    )?,
+                    bitfield_size: member.get_bitfield_size(parent.kind_flag),
+                };
+            }
+            BtfTypeDetail::Array(array) => {
+                accesses.push(CoreAccess::Index(index));
+                field = CoreField {
+                    type_id: array.type_id,
+                    bit_offset: core_add_bit_offset(
+                        field.bit_offset,
+                        core_element_bit_offset(btf, array.type_id, index)?,
+                    )?,
+                    bitfield_size: 0,
+                };
+            }
+            _ => {
+                return Err(Error::RelocationUnsupported(format!(
+                    "Access string {access_str} indexes into a {:?}",
+                    parent.kind
+                )))
+            }
+        }
+    }
+    Ok(CoreSpec {
+        root_index,
+        accesses,
+        field,
+    })
+}
+
+/// Looks up the member `name` of a struct or union, descending into anonymous members. The
+/// returned offset is relative to the start of `type_id`.
+fn core_find_member(btf: &Btf, type_id: u32, name: &str, depth: u32) -> Result<Option<CoreField>> {
+    if depth == 0 {
+        return Err(Error::Parse("BTF type nesting is too deep".to_string()));
+    }
+    let parent = btf.resolve(type_id)?;
+    let BtfTypeDetail::Struct(members) = &parent.detail else {
+        return Ok(None);
+    };
+    for member in members {
+        let member_name = btf.name_by_offset(member.name_off)?;
+        let bit_offset = member.get_offset(parent.kind_flag);
+        if member_name == name {
+            return Ok(Some(CoreField {
+                type_id: member.type_id,
+                bit_offset,
+                bitfield_size: member.get_bitfield_size(parent.kind_flag),
+            }));
+        }
+        if member_name.is_empty()
+            && let Some(field) = core_find_member(btf, member.type_id, name, depth - 1)?
+        {
+            return Ok(Some(CoreField {
+                bit_offset: core_add_bit_offset(bit_offset, field.bit_offset)?,
+                ..field
+            }));
+        }
+    }
+    Ok(None)
+}
+
+/// Checks that a local field may be read from the target field, following libbpf's
+/// `bpf_core_fields_are_compat`: composites match any composite, integers any integer.
+fn core_fields_are_compat(
+    local_btf: &Btf,
+    local_id: u32,
+    target_btf: &Btf,
+    target_id: u32,
+    depth: u32,
+) -> Result<bool> {
+    if depth == 0 {
+        return Err(Error::Parse("BTF type nesting is too deep".to_string()));
+    }
+    let local = local_btf.resolve(local_id)?;
+    let target = target_btf.resolve(target_id)?;
+    let is_composite = |kind| matches!(kind, BtfKind::Struct | BtfKind::Union);
+    if is_composite(local.kind) && is_composite(target.kind) {
+        return Ok(true);
+    }
+    if !core_kinds_compatible(local.kind, target.kind) {
+        return Ok(false);
+    }
+    match (&local.kind, &local.detail, &target.detail) {
+        (BtfKind::Ptr | BtfKind::Float, _, _) => Ok(true),
+        (BtfKind::Fwd | BtfKind::Enum | BtfKind::Enum64, _, _) => {
+            Ok(essential_name(local_btf.name_of(local)?)
+                == essential_name(target_btf.name_of(target)?))
+        }
+        (BtfKind::Int, BtfTypeDetail::Int(local_int), BtfTypeDetail::Int(target_int)) => {
+            Ok(local_int.offset == 0 && target_int.offset == 0)
+        }
+        (BtfKind::Array, BtfTypeDetail::Array(local_array), BtfTypeDetail::Array(target_array)) => {
+            core_fields_are_compat(
+                local_btf,
+                local_array.type_id,
+                target_btf,
+                target_array.type_id,
+                depth - 1,
+            )
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Replays a local spec on the target candidate `target_id`, matching members by name.
+/// Returns `None` if the target lacks one of the accessed members or array elements.
+fn core_match_spec(
+    local_btf: &Btf,
+    local_spec: &CoreSpec,
+    target_btf: &Btf,
+    target_id: u32,
+) -> Result<Option<CoreField>> {
+    let mut field = CoreField {
+        type_id: target_id,
+        bit_offset: core_element_bit_offset(target_btf, target_id, local_spec.root_index)?,
+        bitfield_size: 0,
+    };
+    for access in &local_spec.accesses {
+        match access {
+            CoreAccess::Member { name, type_id } => {
+                let Some(member) =
+                    core_find_member(target_btf, field.type_id, name, CORE_MAX_DEPTH)?
+                else {
+                    return Ok(None);
+                };
+                if !core_fields_are_compat(
+                    local_btf,
+                    *type_id,
+                    target_btf,
+                    member.type_id,
+                    CORE_MAX_DEPTH,
+                )? {
+                    return Ok(None);
+                }
+                field = CoreField {
+                    bit_offset: core_add_bit_offset(field.bit_offset, member.bit_offset)?,
+                    ..member
+                };
+            }
+            CoreAccess::Index(index) => {
+                let parent = target_btf.resolve(field.type_id)?;
+                let BtfTypeDetail::Array(array) = &parent.detail else {
+                    return Ok(None);
+                };
+                // flexible array members have no elements in BTF
+                if *index >= array.nelems && array.nelems != 0 {
+                    return Ok(None);
                 }
+                field = CoreField {
+                    type_id: array.type_id,
+                    bit_offset: core_add_bit_offset(
+                        field.bit_offset,
+                        core_element_bit_offset(target_btf, array.type_id, *index)?,
+                    )?,
+                    bitfield_size: 0,
+                };
+            }
+        }
+    }
+    Ok(Some(field))
+}
+
+/// Computes the value a field relocation resolves to, following libbpf's
+/// `bpf_core_calc_field_relo`. Bitfields are described by the smallest naturally aligned load
+/// of at most 8 bytes that covers them.
+fn core_field_value(btf: &Btf, relo_kind: BpfCoreReloKind, field: &CoreField) -> Result<u32> {
+    let member_type = btf.resolve(field.type_id)?;
+    let bit_offset = field.bit_offset;
+    let (byte_offset, byte_size, bit_size) = if field.bitfield_size > 0 {
+        let bit_size = field.bitfield_size;
+        let mut byte_size = member_type.size_or_type;
+        if byte_size == 0 {
+            return Err(Error::Parse("Bitfield of a zero-sized type".to_string()));
+        }
+        let mut byte_offset = bit_offset / 8 / byte_size * byte_size;
+        while bit_offset + bit_size - byte_offset * 8 > byte_size * 8 {
+            if byte_size >= 8 {
+                return Err(Error::RelocationUnsupported(format!(
+                    "Bitfield at bit {bit_offset} cannot be loaded with a single instruction"
+                )));
             }
-            _ => unimplemented!(),
+            byte_size *= 2;
+            byte_offset = bit_offset / 8 / byte_size * byte_size;
+        }
+        (byte_offset, byte_size, bit_size)
+    } else {
+        let byte_size = btf.size_of(field.type_id)?;
+        (bit_offset / 8, byte_size, byte_size * 8)
+    };
+
+    match relo_kind {
+        BpfCoreReloKind::FieldByteOffset => Ok(byte_offset),
+        BpfCoreReloKind::FieldByteSize => Ok(byte_size),
+        BpfCoreReloKind::FieldExists => Ok(1),
+        BpfCoreReloKind::FieldSigned => {
+            let signed = match (&member_type.kind, &member_type.detail) {
+                (BtfKind::Enum | BtfKind::Enum64, _) => member_type.is_signed_enum(),
+                (_, BtfTypeDetail::Int(int)) => int.encoding & BTF_INT_SIGNED != 0,
+                _ => false,
+            };
+            Ok(signed as u32)
+        }
+        // the loaded value is shifted left to drop the bits above the field, then right
+        // (logically or arithmetically) to drop the bits below it
+        BpfCoreReloKind::FieldLShiftU64 | BpfCoreReloKind::FieldRShiftU64 => {
+            let bits_to_drop = if matches!(relo_kind, BpfCoreReloKind::FieldLShiftU64) {
+                bit_offset + bit_size - byte_offset * 8
+            } else {
+                bit_size
+            };
+            // fields that do not fit the 8-byte load cannot be extracted by shifting
+            64u32.checked_sub(bits_to_drop).ok_or_else(|| {
+                Error::RelocationUnsupported(format!(
+                    "{relo_kind:?} on a field that does not fit in 64 bits"
+                ))
+            })
+        }
+        _ => Err(Error::RelocationUnsupported(format!(
+            "{relo_kind:?} is not a field relocation"
+        ))),
+    }
+}
+
+/// Helper id that poisoned instructions call. The verifier reports a reachable one as
+/// `invalid func unknown#195896080`, the same marker libbpf uses.
+pub const BPF_CORE_POISON_HELPER: i32 = 0xbad2310;
+
+/// A CO-RE relocation whose type or field is missing from the target BTF.
+#[derive(Debug)]
+pub struct PoisonedRelocation {
+    pub section_name: String,
+    /// Byte offset of the poisoned instruction within its section.
+    pub insn_off: u32,
+    pub kind: BpfCoreReloKind,
+    pub reason: Error,
+}
+
+/// Replaces the instruction at `insn_off` with a call to [`BPF_CORE_POISON_HELPER`]. Both
+/// halves of a `BPF_LD_IMM64` are replaced so that the verifier does not trip over the
+/// orphaned second half first.
+fn poison_core_insn(data: &mut [u8], insn_off: usize) -> Result<()> {
+    let is_ld_imm64 = data.get(insn_off) == Some(&BPF_LD_IMM64);
+    let len = if is_ld_imm64 {
+        2 * BPF_INSN_SIZE
+    } else {
+        BPF_INSN_SIZE
+    };
+    let insns = data
+        .get_mut(insn_off..insn_off + len)
+        .with_context(|| format!("Instruction offset {insn_off} out of bounds"))?;
+    for insn in insns.chunks_exact_mut(BPF_INSN_SIZE) {
+        insn[0] = BPF_JMP_CALL;
+        insn[1] = 0;
+        insn[2..4].copy_from_slice(&0i16.to_le_bytes());
+        insn[4..8].copy_from_slice(&BPF_CORE_POISON_HELPER.to_le_bytes());
+    }
+    Ok(())
+}
+
+/// The value a relocated instruction holds as compiled against the program's own BTF, and the
+/// value it must hold on the running kernel.
+struct CoreReloValue {
+    local: u64,
+    target: u64,
+    /// Bitfield accesses are not checked against the instruction, as the compiler is free to
+    /// load them differently.
+    validate: bool,
+}
+
+/// Patches the instruction at `insn_off` with the relocated value: the offset of memory
+/// accesses, the immediate of ALU instructions or the 64-bit immediate of `BPF_LD_IMM64`.
+/// The original value must match what the local BTF predicts, so that a relocation pointing at
+/// an unrelated instruction is caught instead of silently corrupting it.
+fn patch_core_insn(data: &mut [u8], insn_off: usize, value: &CoreReloValue) -> Result<()> {
+    let code = *data
+        .get(insn_off)
+        .with_context(|| format!("Instruction offset {insn_off} out of bounds"))?;
+    let len = if code == BPF_LD_IMM64 {
+        2 * BPF_INSN_SIZE
+    } else {
+        BPF_INSN_SIZE
+    };
+    let insn = data
+        .get_mut(insn_off..insn_off + len)
+        .with_context(|| format!("Instruction offset {insn_off} out of bounds"))?;
+    let (local, target) = (value.local, value.target);
+    let check = |matches: bool| {
+        if value.validate && !matches {
+            return Err(Error::RelocationUnsupported(format!(
+                "Instruction {code:#04x} at offset {insn_off} does not hold the expected value \
+                 {local}"
+            )));
         }
+        Ok(())
+    };
+    let too_big = |field: &str| {
+        Error::RelocationUnsupported(format!(
+            "Value {target} does not fit the {field} of instruction {code:#04x} at offset \
+             {insn_off}"
+        ))
+    };
+
+    match code & BPF_CLASS_MASK {
+        _ if code == BPF_LD_IMM64 => {
+            let lo = u32::from_le_bytes(insn[4..8].try_into().unwrap());
+            let hi = u32::from_le_bytes(insn[12..16].try_into().unwrap());
+            check(((hi as u64) << 32 | lo as u64) == local)?;
+            insn[4..8].copy_from_slice(&(target as u32).to_le_bytes());
+            insn[12..16].copy_from_slice(&((target >> 32) as u32).to_le_bytes());
+        }
+        BPF_CLASS_LDX | BPF_CLASS_ST | BPF_CLASS_STX => {
+            let off = i16::from_le_bytes([insn[2], insn[3]]);
+            check(off as i64 as u64 == local)?;
+            let off = i16::try_from(target as i64).map_err(|_| too_big("offset"))?;
+            insn[2..4].copy_from_slice(&off.to_le_bytes());
+        }
+        BPF_CLASS_ALU | BPF_CLASS_ALU64 if code & BPF_SRC_X == 0 => {
+            let imm = i32::from_le_bytes(insn[4..8].try_into().unwrap());
+            check(imm as u32 as u64 == local || imm as i64 as u64 == local)?;
+            // the immediate is sign-extended, so negative 64-bit values fit as well
+            let imm = i32::try_from(target as i64)
+                .map(|imm| imm as u32)
+                .or_else(|_| u32::try_from(target))
+                .map_err(|_| too_big("immediate"))?;
+            insn[4..8].copy_from_slice(&imm.to_le_bytes());
+        }
+        _ => {
+            return Err(Error::RelocationUnsupported(format!(
+                "CO-RE relocation of instruction {code:#04x} at offset {insn_off}"
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Reduces the values a relocation resolved to on each matching candidate to a single value.
+/// Like libbpf, candidates that disagree make the relocation ambiguous.
+fn core_agreed_value(type_name: &str, values: &[u64]) -> Result<Option<u64>> {
+    match values.split_first() {
+        None => Ok(None),
+        Some((&first, rest)) if rest.iter().all(|&value| value == first) => Ok(Some(first)),
+        Some(_) => Err(Error::AmbiguousRelocation(format!(
+            "Candidates for {type_name} resolve to different values: {values:?}"
+        ))),
     }
 }
 
-fn extract_struct<'a>(btf: &'a Btf<'a>) -> Result<HashMap<&'a str, &'a BtfType>> {
-    let mut struct_map = HashMap::new();
-    for btf_type in &btf.type_section {
-        if btf_type.kind == BtfKind::Struct {
-            let name = common::get_name_from_string_section(
-                btf.string_section,
-                btf_type.name_off as usize,
-            )?;
-            struct_map.insert(name, btf_type);
+fn core_field_relo(
+    local_btf: &Btf,
+    target_btf: &Btf,
+    relo_kind: BpfCoreReloKind,
+    type_id: u32,
+    access_str: &str,
+) -> Result<CoreReloValue> {
+    let local_type = local_btf.get_type(type_id)?;
+    let local_name = local_btf.name_of(local_type)?;
+    let local_spec = core_parse_spec(local_btf, type_id, access_str)?;
+    let local = core_field_value(local_btf, relo_kind, &local_spec.field)? as u64;
+    let validate = local_spec.field.bitfield_size == 0;
+
+    let candidates = find_core_candidates(local_btf, local_type, target_btf)?;
+    if candidates.is_empty() && !matches!(relo_kind, BpfCoreReloKind::FieldExists) {
+        return Err(Error::TypeNotFound(local_name.to_string()));
+    }
+    let mut values = Vec::new();
+    for target_id in candidates {
+        if let Some(target_field) = core_match_spec(local_btf, &local_spec, target_btf, target_id)?
+        {
+            values.push(core_field_value(target_btf, relo_kind, &target_field)? as u64);
+        }
+    }
+    let target = match core_agreed_value(local_name, &values)? {
+        Some(value) => value,
+        None if matches!(relo_kind, BpfCoreReloKind::FieldExists) => 0,
+        None => {
+            return Err(Error::FieldMissing {
+                type_name: local_name.to_string(),
+                field: local_spec.field_path(),
+            });
+        }
+    };
+    Ok(CoreReloValue {
+        local,
+        target,
+        validate,
+    })
+}
+
+fn core_type_relo(
+    local_btf: &Btf,
+    target_btf: &Btf,
+    relo_kind: BpfCoreReloKind,
+    type_id: u32,
+) -> Result<CoreReloValue> {
+    let local = match relo_kind {
+        BpfCoreReloKind::TypeIdLocal | BpfCoreReloKind::TypeIdTarget => type_id as u64,
+        BpfCoreReloKind::TypeSize => local_btf.size_of(type_id)? as u64,
+        _ => 1,
+    };
+    if matches!(relo_kind, BpfCoreReloKind::TypeIdLocal) {
+        return Ok(CoreReloValue {
+            local,
+            target: local,
+            validate: true,
+        });
+    }
+    let local_type = local_btf.get_type(type_id)?;
+    let local_name = local_btf.name_of(local_type)?;
+    let mut values = Vec::new();
+    for candidate in find_core_candidates(local_btf, local_type, target_btf)? {
+        let matched = if matches!(relo_kind, BpfCoreReloKind::TypeMatches) {
+            core_types_match(
+                local_btf,
+                type_id,
+                target_btf,
+                candidate,
+                false,
+                CORE_MAX_DEPTH,
+            )?
+        } else {
+            core_types_are_compat(local_btf, type_id, target_btf, candidate, CORE_MAX_DEPTH)?
+        };
+        if !matched {
+            continue;
+        }
+        values.push(match relo_kind {
+            BpfCoreReloKind::TypeIdTarget => candidate as u64,
+            BpfCoreReloKind::TypeSize => target_btf.size_of(candidate)? as u64,
+            _ => 1,
+        });
+    }
+    let target = match core_agreed_value(local_name, &values)? {
+        Some(value) => value,
+        None if matches!(
+            relo_kind,
+            BpfCoreReloKind::TypeExists | BpfCoreReloKind::TypeMatches
+        ) =>
+        {
+            0
+        }
+        None => return Err(Error::TypeNotFound(local_name.to_string())),
+    };
+    Ok(CoreReloValue {
+        local,
+        target,
+        validate: true,
+    })
+}
+
+fn core_enum_relo(
+    local_btf: &Btf,
+    target_btf: &Btf,
+    relo_kind: BpfCoreReloKind,
+    type_id: u32,
+    access_str: &str,
+) -> Result<CoreReloValue> {
+    // enumerator relocations carry the bare enumerator index, without a root array index
+    let value_index = match parse_access_str(access_str)?[..] {
+        [index] => index as usize,
+        _ => {
+            return Err(Error::Parse(format!(
+                "Invalid enum value access string: {access_str}"
+            )));
+        }
+    };
+    let local_type = local_btf.get_type(type_id)?;
+    let local_name = local_btf.name_of(local_type)?;
+    let local_values = enum_values(local_btf, local_btf.resolve(type_id)?)?;
+    let (value_name, local_value) = local_values
+        .get(value_index)
+        .with_context(|| format!("Invalid enum value index in {access_str}"))?;
+    let local = match relo_kind {
+        BpfCoreReloKind::EnumValExists => 1,
+        _ => *local_value,
+    };
+
+    let mut values = Vec::new();
+    for candidate in find_core_candidates(local_btf, local_type, target_btf)? {
+        let target_type = target_btf.resolve(candidate)?;
+        if !matches!(target_type.kind, BtfKind::Enum | BtfKind::Enum64) {
+            continue;
+        }
+        let target_value = enum_values(target_btf, target_type)?
+            .into_iter()
+            .find(|(name, _)| essential_name(name) == essential_name(value_name));
+        if let Some((_, value)) = target_value {
+            values.push(match relo_kind {
+                BpfCoreReloKind::EnumValExists => 1,
+                _ => value,
+            });
+        }
+    }
+    let target = match core_agreed_value(local_name, &values)? {
+        Some(value) => value,
+        None if matches!(relo_kind, BpfCoreReloKind::EnumValExists) => 0,
+        None => {
+            return Err(Error::FieldMissing {
+                type_name: local_name.to_string(),
+                field: value_name.to_string(),
+            });
+        }
+    };
+    Ok(CoreReloValue {
+        local,
+        target,
+        validate: true,
+    })
+}
+
+/// Kernel BTF that CO-RE relocations are resolved against. Module BTF is only parsed once a
+/// relocation has no candidate in vmlinux, and is then kept for the following relocations.
+#[derive(Debug)]
+pub struct CoreTargets<'a> {
+    vmlinux: &'a Btf<'a>,
+    kernel_btf: Option<&'a KernelBtf>,
+    modules: OnceLock<Vec<Btf<'a>>>,
+}
+
+impl<'a> CoreTargets<'a> {
+    /// `kernel_btf` supplies the module BTF; without it only `vmlinux` is searched.
+    pub fn new(vmlinux: &'a Btf<'a>, kernel_btf: Option<&'a KernelBtf>) -> CoreTargets<'a> {
+        CoreTargets {
+            vmlinux,
+            kernel_btf,
+            modules: OnceLock::new(),
+        }
+    }
+
+    fn modules(&self) -> Result<&[Btf<'a>]> {
+        if let Some(modules) = self.modules.get() {
+            return Ok(modules);
+        }
+        let mut modules = Vec::new();
+        if let Some(kernel_btf) = self.kernel_btf {
+            for module_bin in kernel_btf.modules()? {
+                modules.push(btf_parser::parse_split_btf(module_bin, 0, self.vmlinux)?);
+            }
         }
+        Ok(self.modules.get_or_init(|| modules))
     }
-    Ok(struct_map)
 }
 
 pub fn core_relocate<'a, 'b>(
     data: &'b mut [u8],
     data_section_name: &str,
-    vmlinux: &'a Btf<'a>,
+    targets: &'a CoreTargets<'a>,
     prog_btf: &'b Btf<'b>,
-    prog_btf_ext: &'b BtfExt<'b>,
-) -> Result<()> {
-    let vmlinux_struct_map = extract_struct(vmlinux)?;
+    prog_btf_ext: &'b BtfExt,
+) -> Result<Vec<PoisonedRelocation>> {
+    let mut poisoned = Vec::new();
 
     for BtfExtInfoSec {
         sec_name_off,
         data: relo_data,
     } in &prog_btf_ext.core_relo_part
     {
-        let sec_name =
-            common::get_name_from_string_section(prog_btf.string_section, *sec_name_off as usize)?;
+        let sec_name = prog_btf.name_by_offset(*sec_name_off)?;
         if sec_name != data_section_name {
             continue;
         }
@@ -141,77 +1176,270 @@ pub fn core_relocate<'a, 'b>(
             kind: relo_kind,
         } in relo_data
         {
-            let access_str = common::get_name_from_string_section(
-                prog_btf.string_section,
-                *access_str_off as usize,
-            )?;
-            match relo_kind {
-                BpfCoreReloKind::FieldByteOffset => {
-                    let field_index = access_str[2..]
-                        .parse::<usize>()
-                        .context("Failed to parse field index")?;
-                    let BtfType {
-                        name_off,
-                        detail: BtfTypeDetail::Struct(members),
-                        kind_flag,
-                        ..
-                    } = prog_btf
-                        .type_section
-                        .get(*type_id as usize)
-                        .context("Failed to get type")?
-                    else {
-                        unimplemented!()
-                    };
-                    let struct_name = common::get_name_from_string_section(
-                        prog_btf.string_section,
-                        *name_off as usize,
-                    )?;
-                    let field = members.get(field_index).context("Failed to get field")?;
-                    let field_name = common::get_name_from_string_section(
-                        prog_btf.string_section,
-                        field.name_off as usize,
-                    )?;
-                    let prog_offset = field.get_offset(*kind_flag);
-
-                    let vmlinux_ty = vmlinux_struct_map
-                        .get(struct_name)
-                        .context("Failed to get type from vmlinux btf")?;
-
-                    let BtfType {
-                        detail: BtfTypeDetail::Struct(vmlinux_members),
-                        ..
-                    } = vmlinux_struct_map
-                        .get(struct_name)
-                        .context("Failed to get type from vmlinux btf")?
-                    else {
-                        unreachable!()
-                    };
-                    let vmlinux_field = vmlinux_members
-                        .iter()
-                        .find(|f| {
-                            let name = common::get_name_from_string_section(
-                                vmlinux.string_section,
-                                f.name_off as usize,
-                            )
-                            .unwrap();
-                            name == field_name
-                        })
-                        .context("Failed to find field")?;
-                    let vmlinux_offset = vmlinux_field.get_offset(vmlinux_ty.kind_flag);
-                    if prog_offset != vmlinux_offset {
-                        let insn_off = *insn_off as usize;
-                        data[insn_off + 2..insn_off + 4]
-                            .copy_from_slice(&((vmlinux_offset / 8) as u16).to_le_bytes());
+            let access_str = prog_btf.name_by_offset(*access_str_off)?;
+            // like libbpf, module BTF is only searched when vmlinux has no candidate at all
+            let mut target_btf = targets.vmlinux;
+            if !matches!(relo_kind, BpfCoreReloKind::TypeIdLocal) {
+                let local_type = prog_btf.get_type(*type_id)?;
+                if find_core_candidates(prog_btf, local_type, targets.vmlinux)?.is_empty() {
+                    for module_btf in targets.modules()? {
+                        if !find_core_candidates(prog_btf, local_type, module_btf)?.is_empty() {
+                            target_btf = module_btf;
+                            break;
+                        }
                     }
                 }
-                _ => unimplemented!(),
+            }
+            let result = match relo_kind {
+                BpfCoreReloKind::FieldByteOffset
+                | BpfCoreReloKind::FieldByteSize
+                | BpfCoreReloKind::FieldExists
+                | BpfCoreReloKind::FieldSigned
+                | BpfCoreReloKind::FieldLShiftU64
+                | BpfCoreReloKind::FieldRShiftU64 => {
+                    core_field_relo(prog_btf, target_btf, *relo_kind, *type_id, access_str)
+                }
+                BpfCoreReloKind::TypeIdLocal
+                | BpfCoreReloKind::TypeIdTarget
+                | BpfCoreReloKind::TypeExists
+                | BpfCoreReloKind::TypeSize
+                | BpfCoreReloKind::TypeMatches => {
+                    core_type_relo(prog_btf, target_btf, *relo_kind, *type_id)
+                }
+                BpfCoreReloKind::EnumValExists | BpfCoreReloKind::EnumValValue => {
+                    core_enum_relo(prog_btf, target_btf, *relo_kind, *type_id, access_str)
+                }
+            };
+            match result {
+                Ok(value) => patch_core_insn(data, *insn_off as usize, &value)?,
+                // the access may sit in a branch the program never takes on this kernel, so
+                // leave it to the verifier to reject the program only if it is reachable
+                Err(reason @ (Error::TypeNotFound(_) | Error::FieldMissing { .. })) => {
+                    poison_core_insn(data, *insn_off as usize)?;
+                    poisoned.push(PoisonedRelocation {
+                        section_name: sec_name.to_string(),
+                        insn_off: *insn_off,
+                        kind: *relo_kind,
+                        reason,
+                    });
+                }
+                Err(e) => return Err(e),
             }
         }
     }
-    Ok(())
+    Ok(poisoned)
 }
 
 impl Elf {
+    pub fn section_name(&self, section_index: u16) -> Option<&str> {
+        self.section_names
+            .get(section_index as usize)
+            .map(|name| name.as_str())
+    }
+
+    pub fn symbol(&self, sym_idx: u32) -> Option<&Symbol> {
+        self.symbols.get(sym_idx as usize)
+    }
+
+    pub fn symbol_by_name(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|sym| sym.name == name)
+    }
+
+    /// Returns the symbols defined in `section_name`.
+    pub fn symbols_in_section<'a>(
+        &'a self,
+        section_name: &'a str,
+    ) -> impl Iterator<Item = &'a Symbol> + 'a {
+        self.symbols.iter().filter(move |sym| {
+            sym.section_index != SHN_UNDEF
+                && self.section_name(sym.section_index) == Some(section_name)
+        })
+    }
+
+    pub fn is_executable_section(&self, section_name: &str) -> bool {
+        self.shdrs
+            .get(section_name)
+            .is_some_and(|shdr| shdr.sh_flags & SHF_EXECINSTR != 0)
+    }
+
+    /// Returns the function symbol whose body contains `offset` in `section_name`.
+    fn function_containing<'a>(
+        &'a self,
+        section_name: &'a str,
+        offset: usize,
+    ) -> Option<&'a Symbol> {
+        self.symbols_in_section(section_name).find(|sym| {
+            sym.sym_type == SymbolType::Func
+                && (sym.value as usize..(sym.value + sym.size) as usize).contains(&offset)
+        })
+    }
+
+    /// Builds the instruction stream of the program at `offset..offset + size` of
+    /// `section_name`, appending every function it (transitively) calls and rewriting the
+    /// `BPF_PSEUDO_CALL`/`BPF_PSEUDO_FUNC` immediates to point at the appended copies.
+    ///
+    /// `sections` holds the already relocated bodies of all executable sections. Returns the
+    /// instructions and where each function ended up, starting with the program itself.
+    pub fn link_program<'a>(
+        &'a self,
+        sections: &'a HashMap<String, Vec<u8>>,
+        section_name: &'a str,
+        offset: usize,
+        size: usize,
+    ) -> Result<(Vec<u8>, Vec<LinkSegment<'a>>)> {
+        let body = sections
+            .get(section_name)
+            .ok_or_else(|| Error::SectionMissing(section_name.to_string()))?;
+        let mut insns = body
+            .get(offset..offset + size)
+            .context("Program out of section bounds")?
+            .to_vec();
+        let mut segments = vec![LinkSegment {
+            section_name,
+            section_offset: offset,
+            size,
+            insn_offset: 0,
+        }];
+
+        let mut relocations = HashMap::new();
+        for name in sections.keys() {
+            for rel in self
+                .parse_relocation_section(&format!(".rel{name}"))
+                .unwrap_or_default()
+            {
+                relocations.insert((name.as_str(), rel.r_offset as usize), rel);
+            }
+        }
+
+        let mut seg_idx = 0;
+        while seg_idx < segments.len() {
+            let segment = segments[seg_idx].clone();
+            if !segment.size.is_multiple_of(BPF_INSN_SIZE) {
+                return Err(Error::Parse(format!(
+                    "Function at offset {} of {} is not a whole number of instructions",
+                    segment.section_offset, segment.section_name
+                )));
+            }
+            let mut insn_off = 0;
+            while insn_off < segment.size {
+                let pos = segment.insn_offset + insn_off;
+                let section_off = segment.section_offset + insn_off;
+                let insn: [u8; BPF_INSN_SIZE] = insns
+                    .get(pos..pos + BPF_INSN_SIZE)
+                    .and_then(|insn| insn.try_into().ok())
+                    .with_context(|| {
+                        format!(
+                            "Truncated instruction at offset {section_off} of {}",
+                            segment.section_name
+                        )
+                    })?;
+                let opcode = insn[0];
+                let src = insn[1] >> 4;
+                let imm = i32::from_le_bytes(insn[4..8].try_into().unwrap());
+                let rel = relocations.get(&(segment.section_name, section_off));
+                let rel_symbol = |rel: &Elf64Rel| -> Result<(&str, &Symbol)> {
+                    let sym = self
+                        .symbol(rel.sym_idx)
+                        .context("Invalid relocation symbol index")?;
+                    let target_section = self
+                        .section_name(sym.section_index)
+                        .filter(|_| sym.section_index != SHN_UNDEF)
+                        .ok_or_else(|| Error::SymbolMissing(sym.name.clone()))?;
+                    Ok((target_section, sym))
+                };
+
+                // resolve the section and byte offset of the referenced instruction
+                let target = if opcode == BPF_JMP_CALL && src == BPF_PSEUDO_CALL {
+                    match rel {
+                        Some(rel) => {
+                            let (target_section, sym) = rel_symbol(rel)?;
+                            let target_idx =
+                                (sym.value as usize / BPF_INSN_SIZE) as i64 + imm as i64 + 1;
+                            Some((target_section, target_idx * BPF_INSN_SIZE as i64))
+                        }
+                        // calls to static functions in the same section are not relocated
+                        None => Some((
+                            segment.section_name,
+                            section_off as i64 + (imm as i64 + 1) * BPF_INSN_SIZE as i64,
+                        )),
+                    }
+                } else if opcode == BPF_LD_IMM64 {
+                    // only references to defined code are subprogram addresses; maps, globals
+                    // and `__kconfig`/`__ksym` externs are left to the other relocation passes
+                    match rel.map(rel_symbol).and_then(|target| target.ok()) {
+                        Some((target_section, sym))
+                            if self.is_executable_section(target_section) =>
+                        {
+                            Some((target_section, sym.value as i64 + imm as i64))
+                        }
+                        _ => None,
+                    }
+                } else {
+                    None
+                };
+
+                if let Some((target_section, target_off)) = target {
+                    if target_off < 0 || !(target_off as usize).is_multiple_of(BPF_INSN_SIZE) {
+                        return Err(Error::Parse(format!(
+                            "Invalid call target in {}",
+                            segment.section_name
+                        )));
+                    }
+                    let target_off = target_off as usize;
+                    let existing = segments.iter().find(|seg| {
+                        seg.section_name == target_section
+                            && (seg.section_offset..seg.section_offset + seg.size)
+                                .contains(&target_off)
+                    });
+                    let target_pos = match existing {
+                        Some(seg) => seg.insn_offset + target_off - seg.section_offset,
+                        None => {
+                            let func = self
+                                .function_containing(target_section, target_off)
+                                .with_context(|| {
+                                    format!(
+                                        "No function at offset {target_off} of {target_section}"
+                                    )
+                                })?;
+                            let func_start = func.value as usize;
+                            let func_size = func.size as usize;
+                            let func_body = sections
+                                .get(target_section)
+                                .and_then(|body| body.get(func_start..func_start + func_size))
+                                .with_context(|| format!("Function {} out of bounds", func.name))?;
+                            let insn_offset = insns.len();
+                            insns.extend_from_slice(func_body);
+                            segments.push(LinkSegment {
+                                section_name: target_section,
+                                section_offset: func_start,
+                                size: func_size,
+                                insn_offset,
+                            });
+                            insn_offset + target_off - func_start
+                        }
+                    };
+                    let new_imm =
+                        (target_pos / BPF_INSN_SIZE) as i64 - (pos / BPF_INSN_SIZE) as i64 - 1;
+                    let new_src = if opcode == BPF_JMP_CALL {
+                        BPF_PSEUDO_CALL
+                    } else {
+                        BPF_PSEUDO_FUNC
+                    };
+                    insns[pos + 1] = (insns[pos + 1] & 0x0f) | (new_src << 4);
+                    insns[pos + 4..pos + 8].copy_from_slice(&(new_imm as i32).to_le_bytes());
+                }
+
+                insn_off += if opcode == BPF_LD_IMM64 {
+                    2 * BPF_INSN_SIZE
+                } else {
+                    BPF_INSN_SIZE
+                };
+            }
+            seg_idx += 1;
+        }
+        Ok((insns, segments))
+    }
+
     pub fn get_section_body(&self, section_name: &str) -> Option<&[u8]> {
         if let Some(shdr) = self.shdrs.get(section_name) {
             let start = shdr.sh_offset as usize;
@@ -254,3 +1482,110 @@ impl Elf {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insn(code: u8, off: i16, imm: i32) -> Vec<u8> {
+        let mut insn = vec![code, 0x21];
+        insn.extend_from_slice(&off.to_le_bytes());
+        insn.extend_from_slice(&imm.to_le_bytes());
+        insn
+    }
+
+    fn ld_imm64(value: u64) -> Vec<u8> {
+        let mut insns = insn(BPF_LD_IMM64, 0, value as u32 as i32);
+        insns.extend(insn(0, 0, (value >> 32) as u32 as i32));
+        insns
+    }
+
+    fn value(local: u64, target: u64) -> CoreReloValue {
+        CoreReloValue {
+            local,
+            target,
+            validate: true,
+        }
+    }
+
+    #[test]
+    fn patches_memory_access_offsets() {
+        // ldxdw, stdw and stxdw
+        for code in [0x79, 0x7a, 0x7b] {
+            let mut data = insn(code, 8, 7);
+            patch_core_insn(&mut data, 0, &value(8, 24)).unwrap();
+            assert_eq!(data, insn(code, 24, 7));
+        }
+    }
+
+    #[test]
+    fn patches_alu_immediates() {
+        // mov64 and mov32 with an immediate
+        for code in [0xb7, 0xb4] {
+            let mut data = insn(code, 0, 4);
+            patch_core_insn(&mut data, 0, &value(4, 16)).unwrap();
+            assert_eq!(data, insn(code, 0, 16));
+        }
+        let mut data = insn(0xb7, 0, -1);
+        patch_core_insn(&mut data, 0, &value(u64::MAX, u32::MAX as u64)).unwrap();
+        assert_eq!(data, insn(0xb7, 0, -1));
+    }
+
+    #[test]
+    fn patches_both_halves_of_ld_imm64() {
+        let mut data = [insn(0xb7, 0, 0), ld_imm64(1)].concat();
+        patch_core_insn(&mut data, BPF_INSN_SIZE, &value(1, 0x1_0000_0002)).unwrap();
+        assert_eq!(data, [insn(0xb7, 0, 0), ld_imm64(0x1_0000_0002)].concat());
+    }
+
+    #[test]
+    fn checks_the_original_value() {
+        let mut data = insn(0x79, 8, 0);
+        assert!(matches!(
+            patch_core_insn(&mut data, 0, &value(16, 24)),
+            Err(Error::RelocationUnsupported(_))
+        ));
+        assert_eq!(data, insn(0x79, 8, 0));
+
+        let bitfield = CoreReloValue {
+            validate: false,
+            ..value(16, 24)
+        };
+        patch_core_insn(&mut data, 0, &bitfield).unwrap();
+        assert_eq!(data, insn(0x79, 24, 0));
+    }
+
+    #[test]
+    fn rejects_instructions_it_cannot_patch() {
+        // mov64 from a register, a jump and an offset that does not fit 16 bits
+        for (data, target) in [
+            (insn(0xbf, 0, 0), 8),
+            (insn(0x05, 0, 0), 8),
+            (insn(0x79, 0, 0), 0x8000),
+        ] {
+            let mut data = data;
+            assert!(matches!(
+                patch_core_insn(&mut data, 0, &value(0, target)),
+                Err(Error::RelocationUnsupported(_))
+            ));
+        }
+        let mut data = ld_imm64(0)[..BPF_INSN_SIZE].to_vec();
+        assert!(patch_core_insn(&mut data, 0, &value(0, 0)).is_err());
+    }
+
+    #[test]
+    fn poisons_whole_instructions() {
+        let poisoned = [
+            [BPF_JMP_CALL, 0, 0, 0],
+            BPF_CORE_POISON_HELPER.to_le_bytes(),
+        ]
+        .concat();
+        let mut data = ld_imm64(1);
+        poison_core_insn(&mut data, 0).unwrap();
+        assert_eq!(data, poisoned.repeat(2));
+
+        let mut data = insn(0x79, 8, 0);
+        poison_core_insn(&mut data, 0).unwrap();
+        assert_eq!(data, poisoned);
+    }
+}
@@ -1,12 +1,11 @@
-use anyhow::{bail, Context as _, Result};
+use crate::error::{Context as _, Error, Result};
 use std::collections::HashMap;
 use std::fs;
-use std::mem::size_of;
 use std::path::Path;
 
 use crate::{
     common,
-    elf::{Elf, Elf64Ehdr, Elf64Shdr},
+    elf::{Elf, Elf64Ehdr, Elf64Shdr, Elf64Sym, Symbol, SymbolBinding, SymbolType, SHT_SYMTAB},
 };
 
 fn read_section_name_table<'a>(
@@ -14,7 +13,7 @@ fn read_section_name_table<'a>(
     shdrs: &'a [&'a Elf64Shdr],
     shstrndx: usize,
 ) -> Option<&'a [u8]> {
-    let name_section = &shdrs[shstrndx];
+    let name_section = shdrs.get(shstrndx)?;
     let start = name_section.sh_offset as usize;
     let end = start + name_section.sh_size as usize;
     if end <= data.len() {
@@ -24,15 +23,52 @@ fn read_section_name_table<'a>(
     }
 }
 
+fn parse_symbol_table(data: &[u8], shdrs: &[&Elf64Shdr]) -> Result<Vec<Symbol>> {
+    let Some(symtab) = shdrs.iter().find(|sh| sh.sh_type == SHT_SYMTAB) else {
+        return Ok(Vec::new());
+    };
+    let strtab = shdrs
+        .get(symtab.sh_link as usize)
+        .context("Invalid symbol string table index")?;
+    let str_start = strtab.sh_offset as usize;
+    let str_end = str_start + strtab.sh_size as usize;
+    if str_end > data.len() {
+        return Err(Error::Parse(
+            "Symbol string table out of bounds".to_string(),
+        ));
+    }
+    let string_table = &data[str_start..str_end];
+
+    let start = symtab.sh_offset as usize;
+    let end = start + symtab.sh_size as usize;
+    if end > data.len() {
+        return Err(Error::Parse("Symbol table out of bounds".to_string()));
+    }
+    let mut symbols = Vec::new();
+    for offset in (start..end).step_by(size_of::<Elf64Sym>()) {
+        let sym = common::read_struct::<Elf64Sym>(data, offset).context("Failed to read symbol")?;
+        let name = common::get_name_from_string_section(string_table, sym.st_name as usize)?;
+        symbols.push(Symbol {
+            name: name.to_string(),
+            section_index: sym.st_shndx,
+            value: sym.st_value,
+            size: sym.st_size,
+            binding: SymbolBinding::from(sym.st_info >> 4),
+            sym_type: SymbolType::from(sym.st_info & 0xf),
+        });
+    }
+    Ok(symbols)
+}
+
 pub fn parse_elf<P: AsRef<Path>>(path: P) -> Result<Elf> {
-    let data = fs::read(path).context("Failed to read ELF file")?;
+    let data = fs::read(path)?;
 
     let ehdr = common::read_struct::<Elf64Ehdr>(&data, 0)
         .context("File too small for ELF header")?
         .clone();
 
     if &ehdr.e_ident[0..4] != b"\x7fELF" {
-        bail!("Not an ELF file");
+        return Err(Error::Parse("Not an ELF file".to_string()));
     }
 
     let shoff = ehdr.e_shoff as usize;
@@ -50,18 +86,26 @@ pub fn parse_elf<P: AsRef<Path>>(path: P) -> Result<Elf> {
     let name_table = read_section_name_table(&data, &shdrs, ehdr.e_shstrndx as usize)
         .context("Invalid section name table")?;
 
-    let section_map: HashMap<String, Elf64Shdr> = shdrs
+    let section_names = shdrs
         .iter()
-        .map(|&sh| {
+        .map(|sh| {
             let name = common::get_name_from_string_section(name_table, sh.sh_name as usize)?;
-            Ok((name.to_string(), sh.clone()))
+            Ok(name.to_string())
         })
-        .collect::<Result<_>>()?;
+        .collect::<Result<Vec<_>>>()?;
+    let section_map: HashMap<String, Elf64Shdr> = section_names
+        .iter()
+        .zip(&shdrs)
+        .map(|(name, &sh)| (name.clone(), sh.clone()))
+        .collect();
+    let symbols = parse_symbol_table(&data, &shdrs)?;
     let section_name_table = Some(name_table.to_vec());
     Ok(Elf {
         data,
         ehdr: ehdr.clone(),
         shdrs: section_map,
         section_name_table,
+        section_names,
+        symbols,
     })
 }
@@ -0,0 +1,132 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Reading an input file failed.
+    Io(std::io::Error),
+    /// The ELF, BTF or BTF.ext data is malformed.
+    Parse(String),
+    SectionMissing(String),
+    SymbolMissing(String),
+    /// A relocation type or CO-RE relocation kind the loader cannot apply.
+    RelocationUnsupported(String),
+    /// Several CO-RE candidate types matched a relocation but disagree on its value.
+    AmbiguousRelocation(String),
+    TypeNotFound(String),
+    FieldMissing {
+        type_name: String,
+        field: String,
+    },
+    /// The kernel refused to load a program; `log` holds the verifier output.
+    VerifierRejected {
+        program: String,
+        source: std::io::Error,
+        log: String,
+    },
+    /// A system call other than program loading failed.
+    Syscall {
+        call: &'static str,
+        source: std::io::Error,
+    },
+    /// The caller passed a value that does not fit the object (e.g. a wrongly sized variable).
+    InvalidArgument(String),
+    /// Another error with a description of what was being done, e.g. which map was created.
+    Context {
+        context: String,
+        source: Box<Error>,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Returns the errno of a failed system call, if that is what caused the error.
+    pub fn errno(&self) -> Option<i32> {
+        match self {
+            Error::Io(source)
+            | Error::VerifierRejected { source, .. }
+            | Error::Syscall { source, .. } => source.raw_os_error(),
+            Error::Context { source, .. } => source.errno(),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(source) => write!(f, "I/O error: {source}"),
+            Error::Parse(msg) => write!(f, "Parse error: {msg}"),
+            Error::SectionMissing(name) => write!(f, "Section {name} not found"),
+            Error::SymbolMissing(name) => write!(f, "Symbol {name} not found"),
+            Error::RelocationUnsupported(msg) => write!(f, "Unsupported relocation: {msg}"),
+            Error::AmbiguousRelocation(msg) => write!(f, "Ambiguous relocation: {msg}"),
+            Error::TypeNotFound(name) => write!(f, "Type {name} not found"),
+            Error::FieldMissing { type_name, field } => {
+                write!(f, "Field {field} not found in {type_name}")
+            }
+            Error::VerifierRejected {
+                program,
+                source,
+                log,
+            } => write!(f, "Failed to load program {program}: {source}\n{log}"),
+            Error::Syscall { call, source } => write!(f, "{call} failed: {source}"),
+            Error::InvalidArgument(msg) => write!(f, "Invalid argument: {msg}"),
+            Error::Context { context, source } => write!(f, "{context}: {source}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(source)
+            | Error::VerifierRejected { source, .. }
+            | Error::Syscall { source, .. } => Some(source),
+            Error::Context { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<std::str::Utf8Error> for Error {
+    fn from(e: std::str::Utf8Error) -> Self {
+        Error::Parse(e.to_string())
+    }
+}
+
+/// Turns a missing value into an [`Error::Parse`] and wraps errors in [`Error::Context`],
+/// mirroring `anyhow::Context`.
+pub(crate) trait Context<T> {
+    fn context(self, msg: &str) -> Result<T>;
+    fn with_context<F: FnOnce() -> String>(self, f: F) -> Result<T>;
+}
+
+impl<T> Context<T> for Option<T> {
+    fn context(self, msg: &str) -> Result<T> {
+        self.ok_or_else(|| Error::Parse(msg.to_string()))
+    }
+
+    fn with_context<F: FnOnce() -> String>(self, f: F) -> Result<T> {
+        self.ok_or_else(|| Error::Parse(f()))
+    }
+}
+
+impl<T> Context<T> for Result<T> {
+    fn context(self, msg: &str) -> Result<T> {
+        self.with_context(|| msg.to_string())
+    }
+
+    fn with_context<F: FnOnce() -> String>(self, f: F) -> Result<T> {
+        self.map_err(|source| Error::Context {
+            context: f(),
+            source: Box::new(source),
+        })
+    }
+}
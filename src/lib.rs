@@ -1,6 +1,17 @@
 pub mod btf;
+pub mod btf_builder;
+pub mod btf_dedup;
+pub mod btf_dump;
 pub mod btf_parser;
+pub mod btf_rust;
+pub mod btf_sanitize;
+pub mod btf_source;
 pub mod common;
 pub mod elf;
 pub mod elf_parser;
+pub mod error;
+pub mod map;
+pub mod map_parser;
+pub mod object;
 pub mod syscalls_wrapper;
+pub mod verifier_log;
@@ -1,11 +1,12 @@
+use rust_ebpf_loader::btf::BpfLineInfo;
 use rust_ebpf_loader::btf_parser;
-use rust_ebpf_loader::common;
 use rust_ebpf_loader::elf;
 use rust_ebpf_loader::elf_parser;
 use rust_ebpf_loader::syscalls_wrapper;
 use rust_ebpf_loader::syscalls_wrapper::BpfMapType;
 use rust_ebpf_loader::syscalls_wrapper::BpfMapUpdateFlag;
 use rust_ebpf_loader::syscalls_wrapper::BpfProgType;
+use rust_ebpf_loader::verifier_log::VerifierLog;
 
 fn main() -> anyhow::Result<()> {
     let vmlinux_path = "/sys/kernel/btf/vmlinux";
@@ -33,7 +34,7 @@ fn main() -> anyhow::Result<()> {
     elf::core_relocate(
         &mut xdp_section,
         "xdp",
-        &vmlinux_btf,
+        &elf::CoreTargets::new(&vmlinux_btf, None),
         &xdp_btf_section,
         &xdp_btf_ext_section,
     )?;
@@ -45,23 +46,26 @@ fn main() -> anyhow::Result<()> {
         match result {
             Ok(fd) => fd,
             Err(e) => {
-                println!(
-                    "log_bug:\n{}",
-                    String::from_utf8_lossy(
-                        &log_buf
-                            .into_iter()
-                            .take_while(|&c| c != 0)
-                            .collect::<Vec<_>>()
-                    )
-                );
+                // the whole section is loaded as one program, so its line info only needs
+                // byte offsets turned into instruction indices
+                let line_info = xdp_btf_ext_section
+                    .line_info(&xdp_btf_section, "xdp")?
+                    .iter()
+                    .map(|rec| BpfLineInfo {
+                        insn_off: rec.insn_off / 8,
+                        ..rec.clone()
+                    })
+                    .collect::<Vec<_>>();
+                let log = VerifierLog::parse(&String::from_utf8_lossy(&log_buf));
+                println!("log_buf:\n{}", log.annotate(&xdp_btf_section, &line_info)?);
 
-                unsafe { syscalls_wrapper::close(map)? };
+                syscalls_wrapper::close(map)?;
                 return Err(e.into());
             }
         }
     };
     println!(
-        "log_bug:\n{}",
+        "log_buf:\n{}",
         String::from_utf8_lossy(
             &log_buf
                 .into_iter()
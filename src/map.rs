@@ -0,0 +1,230 @@
+use std::path::Path;
+
+use crate::{
+    error::{Context as _, Error, Result},
+    syscalls_wrapper::{self, BpfMapCreateOpts, BpfMapType, BpfMapUpdateFlag},
+};
+
+pub const BPF_FS_ROOT: &str = "/sys/fs/bpf";
+
+/// Programs may only read the map; used for `.rodata`.
+pub const BPF_F_RDONLY_PROG: u32 = 1 << 7;
+
+/// Kind of the single-entry array maps that back an object's global variables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InternalMapKind {
+    Rodata,
+    Data,
+    Bss,
+}
+
+impl InternalMapKind {
+    pub fn from_section_name(section_name: &str) -> Option<InternalMapKind> {
+        let matches = |prefix: &str| {
+            section_name
+                .strip_prefix(prefix)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+        };
+        if matches(".rodata") {
+            Some(InternalMapKind::Rodata)
+        } else if matches(".data") {
+            Some(InternalMapKind::Data)
+        } else if matches(".bss") {
+            Some(InternalMapKind::Bss)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapPinning {
+    None = 0,
+    ByName = 1,
+}
+
+impl TryFrom<u32> for MapPinning {
+    type Error = Error;
+
+    fn try_from(value: u32) -> Result<Self> {
+        match value {
+            0 => Ok(MapPinning::None),
+            1 => Ok(MapPinning::ByName),
+            _ => Err(Error::Parse(format!("Invalid map pinning: {}", value))),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MapDef {
+    pub map_type: BpfMapType,
+    pub key_size: u32,
+    pub value_size: u32,
+    pub max_entries: u32,
+    pub map_flags: u32,
+    pub numa_node: u32,
+    pub map_extra: u64,
+    pub pinning: MapPinning,
+    /// Template for the inner maps of `ArrayOfMaps`/`HashOfMaps`.
+    pub inner: Option<Box<MapDef>>,
+    /// Ids of the key and value types in the object's BTF, or 0 when unknown. The kernel uses
+    /// them to pretty-print the map contents.
+    pub btf_key_type_id: u32,
+    pub btf_value_type_id: u32,
+}
+
+impl Default for MapDef {
+    fn default() -> Self {
+        MapDef {
+            map_type: BpfMapType::Unspec,
+            key_size: 0,
+            value_size: 0,
+            max_entries: 0,
+            map_flags: 0,
+            numa_node: 0,
+            map_extra: 0,
+            pinning: MapPinning::None,
+            inner: None,
+            btf_key_type_id: 0,
+            btf_value_type_id: 0,
+        }
+    }
+}
+
+/// Record layout of the legacy `SEC("maps")` definitions (`struct bpf_map_def`), including the
+/// `inner_map_idx`/`numa_node` extension used by older samples. Objects may use any prefix of it.
+#[repr(C)]
+#[derive(Debug, Clone, Default)]
+pub struct BpfMapDef {
+    pub map_type: u32,
+    pub key_size: u32,
+    pub value_size: u32,
+    pub max_entries: u32,
+    pub map_flags: u32,
+    pub inner_map_idx: u32,
+    pub numa_node: u32,
+}
+
+#[derive(Debug)]
+pub struct Map {
+    pub name: String,
+    pub def: MapDef,
+    pub fd: Option<i32>,
+    /// Set for maps backing a global data section; `data` is then the section contents that
+    /// are written to the map's only entry on creation.
+    pub internal: Option<InternalMapKind>,
+    pub data: Vec<u8>,
+}
+
+fn create_map(name: &str, def: &MapDef, btf_fd: Option<i32>) -> Result<i32> {
+    let inner_map_fd = match &def.inner {
+        Some(inner) => Some(create_map(&format!("{name}.inner"), inner, btf_fd)?),
+        None => None,
+    };
+    // the kernel requires a value type whenever type information is given
+    let btf_fd = btf_fd.filter(|_| def.btf_value_type_id != 0);
+    let mut opts = BpfMapCreateOpts {
+        map_name: Some(name),
+        map_flags: def.map_flags,
+        inner_map_fd,
+        numa_node: def.numa_node,
+        map_extra: def.map_extra,
+        ..BpfMapCreateOpts::default()
+    };
+    if btf_fd.is_some() {
+        opts.btf_fd = btf_fd;
+        opts.btf_key_type_id = def.btf_key_type_id;
+        opts.btf_value_type_id = def.btf_value_type_id;
+    }
+    let create = |opts: &BpfMapCreateOpts| unsafe {
+        syscalls_wrapper::bpf_map_create_opts(
+            def.map_type,
+            def.key_size,
+            def.value_size,
+            def.max_entries,
+            opts,
+        )
+    };
+    let mut result = create(&opts);
+    // like libbpf, fall back to a map without type information when the kernel rejects the
+    // BTF, e.g. because it does not support BTF for this map type
+    if result.is_err() && btf_fd.is_some() {
+        opts.btf_fd = None;
+        opts.btf_key_type_id = 0;
+        opts.btf_value_type_id = 0;
+        result = create(&opts);
+    }
+    // the kernel only needs the inner map as a template while creating the outer map
+    if let Some(inner_map_fd) = inner_map_fd {
+        unsafe { syscalls_wrapper::close(inner_map_fd)? };
+    }
+    result.with_context(|| format!("Failed to create map {name}"))
+}
+
+impl Map {
+    pub fn new_internal(section_name: &str, kind: InternalMapKind, data: Vec<u8>) -> Map {
+        let def = MapDef {
+            map_type: BpfMapType::Array,
+            key_size: 4,
+            value_size: data.len() as u32,
+            max_entries: 1,
+            map_flags: if kind == InternalMapKind::Rodata {
+                BPF_F_RDONLY_PROG
+            } else {
+                0
+            },
+            ..MapDef::default()
+        };
+        Map {
+            name: section_name.to_string(),
+            def,
+            fd: None,
+            internal: Some(kind),
+            data,
+        }
+    }
+
+    pub fn fd(&self) -> Option<i32> {
+        self.fd
+    }
+
+    /// Creates the map in the kernel, or reopens it from bpffs when it is pinned by name and
+    /// already exists. `btf_fd` is the loaded BTF that the definition's type ids refer to.
+    pub fn create(&mut self, btf_fd: Option<i32>) -> Result<i32> {
+        if let Some(fd) = self.fd {
+            return Ok(fd);
+        }
+        let pin_path = format!("{BPF_FS_ROOT}/{}", self.name);
+        if self.def.pinning == MapPinning::ByName && Path::new(&pin_path).exists() {
+            let fd = unsafe { syscalls_wrapper::bpf_obj_get(&pin_path) }
+                .with_context(|| format!("Failed to open pinned map {pin_path}"))?;
+            self.fd = Some(fd);
+            return Ok(fd);
+        }
+
+        let fd = create_map(&self.name, &self.def, btf_fd)?;
+        self.fd = Some(fd);
+        if let Some(kind) = self.internal {
+            if kind != InternalMapKind::Bss {
+                unsafe {
+                    syscalls_wrapper::bpf_map_update_elem(
+                        fd,
+                        &0u32,
+                        self.data.as_slice(),
+                        BpfMapUpdateFlag::Any,
+                    )
+                }
+                .with_context(|| format!("Failed to initialize map {}", self.name))?;
+            }
+            if kind == InternalMapKind::Rodata {
+                unsafe { syscalls_wrapper::bpf_map_freeze(fd) }
+                    .with_context(|| format!("Failed to freeze map {}", self.name))?;
+            }
+        }
+        if self.def.pinning == MapPinning::ByName {
+            unsafe { syscalls_wrapper::bpf_obj_pin(fd, &pin_path) }
+                .with_context(|| format!("Failed to pin map at {pin_path}"))?;
+        }
+        Ok(fd)
+    }
+}
@@ -0,0 +1,230 @@
+use crate::{
+    btf::{Btf, BtfKind, BtfType, BtfTypeDetail},
+    elf::{Elf, SymbolType},
+    error::{Context as _, Error, Result},
+    map::{BpfMapDef, Map, MapDef, MapPinning},
+    syscalls_wrapper::BpfMapType,
+};
+
+/// Decodes `__uint(name, val)`, which is encoded as `int (*name)[val]`.
+fn get_map_uint(btf: &Btf, type_id: u32) -> Result<u32> {
+    let ptr = btf.resolve(type_id)?;
+    if ptr.kind != BtfKind::Ptr {
+        return Err(Error::Parse("Map attribute is not a pointer".to_string()));
+    }
+    let BtfType {
+        detail: BtfTypeDetail::Array(array),
+        ..
+    } = btf.resolve(ptr.size_or_type)?
+    else {
+        return Err(Error::Parse(
+            "Map attribute does not point to an array".to_string(),
+        ));
+    };
+    Ok(array.nelems)
+}
+
+/// Decodes `__type(name, T)`, which is encoded as `T *name`, into the type id of `T`.
+fn get_map_type(btf: &Btf, type_id: u32) -> Result<u32> {
+    let ptr = btf.resolve(type_id)?;
+    if ptr.kind != BtfKind::Ptr {
+        return Err(Error::Parse(
+            "Map type attribute is not a pointer".to_string(),
+        ));
+    }
+    Ok(ptr.size_or_type)
+}
+
+fn parse_map_def(btf: &Btf, map_name: &str, def_type: &BtfType) -> Result<MapDef> {
+    let BtfTypeDetail::Struct(members) = &def_type.detail else {
+        return Err(Error::Parse(format!(
+            "Map {map_name} definition is not a struct"
+        )));
+    };
+    let mut def = MapDef::default();
+    for member in members {
+        let name = btf.name_by_offset(member.name_off)?;
+        match name {
+            "type" => def.map_type = BpfMapType::try_from(get_map_uint(btf, member.type_id)?)?,
+            "max_entries" => def.max_entries = get_map_uint(btf, member.type_id)?,
+            "map_flags" => def.map_flags = get_map_uint(btf, member.type_id)?,
+            "numa_node" => def.numa_node = get_map_uint(btf, member.type_id)?,
+            "map_extra" => def.map_extra = get_map_uint(btf, member.type_id)? as u64,
+            "key_size" => def.key_size = get_map_uint(btf, member.type_id)?,
+            "value_size" => def.value_size = get_map_uint(btf, member.type_id)?,
+            "key" => {
+                def.btf_key_type_id = get_map_type(btf, member.type_id)?;
+                def.key_size = btf.size_of(def.btf_key_type_id)?;
+            }
+            "value" => {
+                def.btf_value_type_id = get_map_type(btf, member.type_id)?;
+                def.value_size = btf.size_of(def.btf_value_type_id)?;
+            }
+            "pinning" => def.pinning = MapPinning::try_from(get_map_uint(btf, member.type_id)?)?,
+            "values" => {
+                // `__array(values, struct inner_def)` is an array of pointers to the inner
+                // definition; for prog arrays it points to a func proto instead
+                let BtfType {
+                    detail: BtfTypeDetail::Array(array),
+                    ..
+                } = btf.resolve(member.type_id)?
+                else {
+                    return Err(Error::Parse(format!(
+                        "Map {map_name} values is not an array"
+                    )));
+                };
+                let ptr = btf.resolve(array.type_id)?;
+                if ptr.kind != BtfKind::Ptr {
+                    return Err(Error::Parse(format!(
+                        "Map {map_name} values is not an array of pointers"
+                    )));
+                }
+                let inner_type = btf.resolve(ptr.size_or_type)?;
+                if inner_type.kind == BtfKind::Struct {
+                    let inner = parse_map_def(btf, &format!("{map_name}.inner"), inner_type)?;
+                    // map-in-map values are always inner map fds
+                    def.value_size = 4;
+                    def.inner = Some(Box::new(inner));
+                }
+            }
+            _ => {
+                return Err(Error::Parse(format!(
+                    "Unknown attribute {name} in map {map_name}"
+                )))
+            }
+        }
+    }
+    if def.map_type == BpfMapType::Unspec {
+        return Err(Error::Parse(format!("Map {map_name} has no type")));
+    }
+    Ok(def)
+}
+
+/// Collects the map definitions declared with `SEC(".maps")` from the object's BTF.
+pub fn parse_btf_maps(btf: &Btf) -> Result<Vec<Map>> {
+    let Some((
+        _,
+        BtfType {
+            detail: BtfTypeDetail::DataSec(vars),
+            ..
+        },
+    )) = btf.find_by_name_kind(".maps", BtfKind::DataSec)
+    else {
+        return Ok(Vec::new());
+    };
+
+    let mut maps = Vec::new();
+    for var_secinfo in vars {
+        let var = btf.get_type(var_secinfo.type_id)?;
+        if var.kind != BtfKind::Var {
+            return Err(Error::Parse(
+                ".maps section entry is not a variable".to_string(),
+            ));
+        }
+        let name = btf.name_of(var)?;
+        let def_type = btf.resolve(var.size_or_type)?;
+        let def = parse_map_def(btf, name, def_type)?;
+        maps.push(Map {
+            name: name.to_string(),
+            def,
+            fd: None,
+            internal: None,
+            data: Vec::new(),
+        });
+    }
+    Ok(maps)
+}
+
+/// Collects the legacy `struct bpf_map_def` records from the `maps` section. The record size is
+/// not recorded anywhere, so like libbpf it is derived from the section size and symbol count.
+pub fn parse_legacy_maps(elf: &Elf) -> Result<Vec<Map>> {
+    let Some(body) = elf.get_section_body("maps") else {
+        return Ok(Vec::new());
+    };
+    let mut symbols = elf
+        .symbols_in_section("maps")
+        .filter(|sym| sym.sym_type != SymbolType::Section)
+        .collect::<Vec<_>>();
+    if symbols.is_empty() {
+        return Ok(Vec::new());
+    }
+    symbols.sort_by_key(|sym| sym.value);
+    if body.len() % symbols.len() != 0 {
+        return Err(Error::Parse(
+            "Unable to determine the map definition size of the maps section".to_string(),
+        ));
+    }
+    let def_size = body.len() / symbols.len();
+    // type, key_size, value_size and max_entries are mandatory
+    if def_size < 4 * std::mem::size_of::<u32>() {
+        return Err(Error::Parse(format!(
+            "Legacy map definitions are too small: {def_size} bytes"
+        )));
+    }
+
+    let mut raw_defs = Vec::new();
+    for sym in &symbols {
+        let start = sym.value as usize;
+        let record = body
+            .get(start..start + def_size)
+            .with_context(|| format!("Map {} out of section bounds", sym.name))?;
+        let len = def_size.min(std::mem::size_of::<BpfMapDef>());
+        if record[len..].iter().any(|&b| b != 0) {
+            return Err(Error::Parse(format!(
+                "Map {} uses unsupported map definition fields",
+                sym.name
+            )));
+        }
+        // fields past the end of a shorter record are treated as zero
+        let field = |index: usize| {
+            let offset = index * std::mem::size_of::<u32>();
+            if offset + 4 > len {
+                0
+            } else {
+                u32::from_le_bytes(record[offset..offset + 4].try_into().unwrap())
+            }
+        };
+        raw_defs.push(BpfMapDef {
+            map_type: field(0),
+            key_size: field(1),
+            value_size: field(2),
+            max_entries: field(3),
+            map_flags: field(4),
+            inner_map_idx: field(5),
+            numa_node: field(6),
+        });
+    }
+
+    let to_map_def = |raw: &BpfMapDef| -> Result<MapDef> {
+        Ok(MapDef {
+            map_type: BpfMapType::try_from(raw.map_type)?,
+            key_size: raw.key_size,
+            value_size: raw.value_size,
+            max_entries: raw.max_entries,
+            map_flags: raw.map_flags,
+            numa_node: raw.numa_node,
+            ..MapDef::default()
+        })
+    };
+    let mut maps = Vec::new();
+    for (sym, raw) in symbols.iter().zip(&raw_defs) {
+        let mut def = to_map_def(raw)?;
+        if matches!(
+            def.map_type,
+            BpfMapType::ArrayOfMaps | BpfMapType::HashOfMaps
+        ) {
+            let inner = raw_defs
+                .get(raw.inner_map_idx as usize)
+                .with_context(|| format!("Invalid inner map index for map {}", sym.name))?;
+            def.inner = Some(Box::new(to_map_def(inner)?));
+        }
+        maps.push(Map {
+            name: sym.name.clone(),
+            def,
+            fd: None,
+            internal: None,
+            data: Vec::new(),
+        });
+    }
+    Ok(maps)
+}
@@ -0,0 +1,740 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::{
+    btf::{BpfFuncInfo, BpfLineInfo, Btf, BtfExt, BtfKind, BtfTypeDetail, BtfVarSecinfo},
+    btf_parser,
+    btf_sanitize::{self, BtfFeatures},
+    btf_source::BtfSource,
+    elf::{
+        self, CoreTargets, Elf, LinkSegment, PoisonedRelocation, RelocationTarget, Symbol,
+        SymbolBinding, SymbolType, BPF_INSN_SIZE, SHF_EXECINSTR, SHN_UNDEF, SHT_NOBITS,
+        SHT_PROGBITS,
+    },
+    elf_parser,
+    error::{Context as _, Error, Result},
+    map::{InternalMapKind, Map},
+    map_parser,
+    syscalls_wrapper::{self, BpfAttachType, BpfMapUpdateFlag, BpfProgLoadOpts, BpfProgType},
+    verifier_log::VerifierLog,
+};
+
+/// Section name prefixes understood by the loader, in the spirit of libbpf's `SEC()` table.
+/// A prefix matches the section name exactly or followed by `/`.
+const SECTION_DEFS: &[(&str, BpfProgType, Option<BpfAttachType>)] = &[
+    ("socket", BpfProgType::SocketFilter, None),
+    ("kprobe", BpfProgType::Kprobe, None),
+    ("kretprobe", BpfProgType::Kprobe, None),
+    ("uprobe", BpfProgType::Kprobe, None),
+    ("uretprobe", BpfProgType::Kprobe, None),
+    ("tc", BpfProgType::SchedCls, None),
+    ("classifier", BpfProgType::SchedCls, None),
+    ("action", BpfProgType::SchedAct, None),
+    ("tracepoint", BpfProgType::Tracepoint, None),
+    ("tp", BpfProgType::Tracepoint, None),
+    ("raw_tracepoint", BpfProgType::RawTracepoint, None),
+    ("raw_tp", BpfProgType::RawTracepoint, None),
+    ("xdp", BpfProgType::Xdp, None),
+    ("perf_event", BpfProgType::PerfEvent, None),
+    ("lwt_in", BpfProgType::LwtIn, None),
+    ("lwt_out", BpfProgType::LwtOut, None),
+    ("lwt_xmit", BpfProgType::LwtXmit, None),
+    ("lwt_seg6local", BpfProgType::LwtSeg6local, None),
+    ("sockops", BpfProgType::SockOps, None),
+    ("sk_skb", BpfProgType::SkSkb, None),
+    ("sk_msg", BpfProgType::SkMsg, None),
+    ("lirc_mode2", BpfProgType::LircMode2, None),
+    ("flow_dissector", BpfProgType::FlowDissector, None),
+    ("sk_reuseport", BpfProgType::SkReuseport, None),
+    (
+        "cgroup_skb/ingress",
+        BpfProgType::CgroupSkb,
+        Some(BpfAttachType::CgroupInetIngress),
+    ),
+    (
+        "cgroup_skb/egress",
+        BpfProgType::CgroupSkb,
+        Some(BpfAttachType::CgroupInetEgress),
+    ),
+    ("cgroup/skb", BpfProgType::CgroupSkb, None),
+    (
+        "cgroup/sock_create",
+        BpfProgType::CgroupSock,
+        Some(BpfAttachType::CgroupInetSockCreate),
+    ),
+    (
+        "cgroup/sock_release",
+        BpfProgType::CgroupSock,
+        Some(BpfAttachType::CgroupInetSockRelease),
+    ),
+    (
+        "cgroup/sock",
+        BpfProgType::CgroupSock,
+        Some(BpfAttachType::CgroupInetSockCreate),
+    ),
+    (
+        "cgroup/post_bind4",
+        BpfProgType::CgroupSock,
+        Some(BpfAttachType::CgroupInet4PostBind),
+    ),
+    (
+        "cgroup/post_bind6",
+        BpfProgType::CgroupSock,
+        Some(BpfAttachType::CgroupInet6PostBind),
+    ),
+    (
+        "cgroup/dev",
+        BpfProgType::CgroupDevice,
+        Some(BpfAttachType::CgroupDevice),
+    ),
+    (
+        "cgroup/bind4",
+        BpfProgType::CgroupSockAddr,
+        Some(BpfAttachType::CgroupInet4Bind),
+    ),
+    (
+        "cgroup/bind6",
+        BpfProgType::CgroupSockAddr,
+        Some(BpfAttachType::CgroupInet6Bind),
+    ),
+    (
+        "cgroup/connect4",
+        BpfProgType::CgroupSockAddr,
+        Some(BpfAttachType::CgroupInet4Connect),
+    ),
+    (
+        "cgroup/connect6",
+        BpfProgType::CgroupSockAddr,
+        Some(BpfAttachType::CgroupInet6Connect),
+    ),
+    (
+        "cgroup/sendmsg4",
+        BpfProgType::CgroupSockAddr,
+        Some(BpfAttachType::CgroupUdp4Sendmsg),
+    ),
+    (
+        "cgroup/sendmsg6",
+        BpfProgType::CgroupSockAddr,
+        Some(BpfAttachType::CgroupUdp6Sendmsg),
+    ),
+    (
+        "cgroup/recvmsg4",
+        BpfProgType::CgroupSockAddr,
+        Some(BpfAttachType::CgroupUdp4Recvmsg),
+    ),
+    (
+        "cgroup/recvmsg6",
+        BpfProgType::CgroupSockAddr,
+        Some(BpfAttachType::CgroupUdp6Recvmsg),
+    ),
+];
+
+pub fn prog_type_from_section(section_name: &str) -> Option<(BpfProgType, Option<BpfAttachType>)> {
+    SECTION_DEFS
+        .iter()
+        .find(|(prefix, _, _)| {
+            section_name
+                .strip_prefix(prefix)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+        .map(|&(_, prog_type, attach_type)| (prog_type, attach_type))
+}
+
+#[derive(Debug)]
+pub struct Program {
+    pub name: String,
+    pub section_name: String,
+    pub prog_type: BpfProgType,
+    pub expected_attach_type: Option<BpfAttachType>,
+    /// Byte offset of the program's entry point within its section.
+    pub section_offset: usize,
+    pub insns: Vec<u8>,
+    pub fd: Option<i32>,
+    /// Function and line info of the linked program from `.BTF.ext`, with `insn_off` counted
+    /// in instructions. Filled in by [`Object::load`].
+    pub func_info: Vec<BpfFuncInfo>,
+    pub line_info: Vec<BpfLineInfo>,
+}
+
+impl Program {
+    pub fn fd(&self) -> Option<i32> {
+        self.fd
+    }
+}
+
+#[derive(Debug)]
+pub struct Object {
+    pub elf: Elf,
+    pub license: String,
+    pub kern_version: u32,
+    pub programs: Vec<Program>,
+    /// Executable sections whose program type the loader does not know; they are left out of
+    /// `programs` instead of failing [`Object::open`].
+    pub unsupported_sections: Vec<String>,
+    pub maps: Vec<Map>,
+    /// Map fds supplied by the caller; these take precedence over `maps` and are not closed.
+    pub map_fds: HashMap<String, i32>,
+    pub log_level: u32,
+    pub log_size: usize,
+    /// Kernel BTF that CO-RE relocations are resolved against.
+    pub btf_source: BtfSource,
+    /// CO-RE relocations that could not be resolved against the running kernel during
+    /// [`Object::load`]; their instructions fail verification only if reachable.
+    pub poisoned_relocations: Vec<PoisonedRelocation>,
+    /// The object's `.BTF` once loaded into the kernel. Programs and maps are still loaded
+    /// without it when the kernel rejects it.
+    pub btf_fd: Option<i32>,
+    /// Why the kernel rejected the object's `.BTF`, including its BTF verifier log, when
+    /// [`Object::load`] had to continue without it.
+    pub btf_load_error: Option<Error>,
+    /// BTF support of the kernel, used to sanitize `.BTF` before loading it. Probed on the
+    /// first [`Object::load`] when not set.
+    pub btf_features: Option<BtfFeatures>,
+}
+
+impl Object {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Object> {
+        let elf = elf_parser::parse_elf(path)?;
+
+        let license = match elf.get_section_body("license") {
+            Some(body) => std::str::from_utf8(body)?
+                .trim_end_matches('\0')
+                .to_string(),
+            None => return Err(Error::SectionMissing("license".to_string())),
+        };
+        let kern_version = match elf.get_section_body("version") {
+            Some(body) => u32::from_le_bytes(body.try_into().map_err(|_| {
+                Error::Parse("Version section must be exactly 4 bytes".to_string())
+            })?),
+            None => 0,
+        };
+
+        let mut sections = elf
+            .shdrs
+            .iter()
+            .filter(|(_, shdr)| {
+                shdr.sh_type == SHT_PROGBITS
+                    && shdr.sh_flags & SHF_EXECINSTR != 0
+                    && shdr.sh_size > 0
+            })
+            .collect::<Vec<_>>();
+        sections.sort_by_key(|(_, shdr)| shdr.sh_offset);
+
+        let mut programs = Vec::new();
+        let mut unsupported_sections = Vec::new();
+        for (section_name, _) in sections {
+            if section_name == ".text" {
+                continue;
+            }
+            // program types the loader does not support yet must not keep the rest of the
+            // object from loading
+            let Some((prog_type, expected_attach_type)) = prog_type_from_section(section_name)
+            else {
+                unsupported_sections.push(section_name.clone());
+                continue;
+            };
+            let body = elf
+                .get_section_body(section_name)
+                .ok_or_else(|| Error::SectionMissing(section_name.clone()))?;
+
+            let mut entries = elf
+                .symbols_in_section(section_name)
+                .filter(|sym| {
+                    sym.sym_type == SymbolType::Func && sym.binding != SymbolBinding::Local
+                })
+                .map(|sym| (sym.name.clone(), sym.value as usize, sym.size as usize))
+                .collect::<Vec<_>>();
+            if entries.is_empty() {
+                // stripped objects have no function symbols; treat the section as one program
+                entries.push((section_name.clone(), 0, body.len()));
+            }
+            for (name, offset, size) in entries {
+                let insns = body
+                    .get(offset..offset + size)
+                    .with_context(|| format!("Program {name} out of section bounds"))?
+                    .to_vec();
+                programs.push(Program {
+                    name,
+                    section_name: section_name.clone(),
+                    prog_type,
+                    expected_attach_type,
+                    section_offset: offset,
+                    insns,
+                    fd: None,
+                    func_info: Vec::new(),
+                    line_info: Vec::new(),
+                });
+            }
+        }
+
+        let btf = match elf.get_section_body(".BTF") {
+            Some(btf) => Some(btf_parser::parse_btf(btf, 0)?),
+            None => None,
+        };
+        let mut maps = match &btf {
+            Some(btf) if elf.shdrs.contains_key(".maps") => map_parser::parse_btf_maps(btf)?,
+            _ => Vec::new(),
+        };
+        maps.extend(map_parser::parse_legacy_maps(&elf)?);
+
+        let mut data_sections = elf
+            .shdrs
+            .iter()
+            .filter(|(_, shdr)| shdr.sh_size > 0)
+            .filter_map(|(name, shdr)| {
+                InternalMapKind::from_section_name(name).map(|kind| (name, shdr, kind))
+            })
+            .collect::<Vec<_>>();
+        data_sections.sort_by_key(|(_, shdr, _)| shdr.sh_offset);
+        for (section_name, shdr, kind) in data_sections {
+            let data = if shdr.sh_type == SHT_NOBITS {
+                vec![0; shdr.sh_size as usize]
+            } else {
+                elf.get_section_body(section_name)
+                    .ok_or_else(|| Error::SectionMissing(section_name.clone()))?
+                    .to_vec()
+            };
+            let mut map = Map::new_internal(section_name, kind, data);
+            // the value of a global data map is described by the section's DATASEC
+            if let Some((type_id, _)) = btf
+                .as_ref()
+                .and_then(|btf| btf.find_by_name_kind(section_name, BtfKind::DataSec))
+            {
+                map.def.btf_value_type_id = type_id;
+            }
+            maps.push(map);
+        }
+
+        Ok(Object {
+            elf,
+            license,
+            kern_version,
+            programs,
+            unsupported_sections,
+            maps,
+            map_fds: HashMap::new(),
+            log_level: 1,
+            log_size: 4096,
+            btf_source: BtfSource::default(),
+            poisoned_relocations: Vec::new(),
+            btf_fd: None,
+            btf_load_error: None,
+            btf_features: None,
+        })
+    }
+
+    /// Registers the fd that `R_BPF_64_64` relocations against the map symbol `name` resolve to.
+    pub fn set_map_fd(&mut self, name: &str, fd: i32) -> Result<()> {
+        if self.elf.symbol_by_name(name).is_none() {
+            return Err(Error::SymbolMissing(name.to_string()));
+        }
+        self.map_fds.insert(name.to_string(), fd);
+        Ok(())
+    }
+
+    /// Finds the global variable `name` and the internal map that holds it.
+    fn global_variable(&self, name: &str) -> Result<(&Symbol, usize)> {
+        let sym = self
+            .elf
+            .symbol_by_name(name)
+            .filter(|sym| sym.section_index != SHN_UNDEF)
+            .ok_or_else(|| Error::SymbolMissing(name.to_string()))?;
+        let section_name = self
+            .elf
+            .section_name(sym.section_index)
+            .context("Invalid section index")?;
+        let map_idx = self
+            .maps
+            .iter()
+            .position(|map| map.internal.is_some() && map.name == section_name)
+            .ok_or_else(|| Error::InvalidArgument(format!("{name} is not a global variable")))?;
+        Ok((sym, map_idx))
+    }
+
+    /// Overrides the initial value of a `.rodata` variable (e.g. a `const volatile` knob).
+    /// Must be called before [`Object::load`], as `.rodata` is frozen once created.
+    pub fn set_rodata(&mut self, name: &str, value: &[u8]) -> Result<()> {
+        let (sym, map_idx) = self.global_variable(name)?;
+        let (offset, size) = (sym.value as usize, sym.size as usize);
+        let map = &mut self.maps[map_idx];
+        if map.internal != Some(InternalMapKind::Rodata) {
+            return Err(Error::InvalidArgument(format!(
+                "{name} is not a .rodata variable"
+            )));
+        }
+        if map.fd.is_some() {
+            return Err(Error::InvalidArgument(format!(
+                "Cannot set {name} after the object has been loaded"
+            )));
+        }
+        if value.len() != size {
+            return Err(Error::InvalidArgument(format!(
+                "{name} is {size} bytes but {} bytes were given",
+                value.len()
+            )));
+        }
+        map.data
+            .get_mut(offset..offset + size)
+            .with_context(|| format!("{name} out of section bounds"))?
+            .copy_from_slice(value);
+        Ok(())
+    }
+
+    /// Reads the current value of a global variable from its map.
+    pub fn read_global(&self, name: &str) -> Result<Vec<u8>> {
+        let (sym, map_idx) = self.global_variable(name)?;
+        let map = &self.maps[map_idx];
+        let fd = map
+            .fd
+            .ok_or_else(|| Error::InvalidArgument(format!("{} has not been loaded", map.name)))?;
+        let mut value = vec![0u8; map.def.value_size as usize];
+        unsafe { syscalls_wrapper::bpf_map_lookup_elem(fd, &0u32, value.as_mut_slice())? };
+        let (offset, size) = (sym.value as usize, sym.size as usize);
+        Ok(value
+            .get(offset..offset + size)
+            .with_context(|| format!("{name} out of section bounds"))?
+            .to_vec())
+    }
+
+    /// Writes a `.data` or `.bss` variable of a loaded object.
+    pub fn write_global(&self, name: &str, new_value: &[u8]) -> Result<()> {
+        let (sym, map_idx) = self.global_variable(name)?;
+        let map = &self.maps[map_idx];
+        if map.internal == Some(InternalMapKind::Rodata) {
+            return Err(Error::InvalidArgument(format!("{name} is read-only")));
+        }
+        let fd = map
+            .fd
+            .ok_or_else(|| Error::InvalidArgument(format!("{} has not been loaded", map.name)))?;
+        let (offset, size) = (sym.value as usize, sym.size as usize);
+        if new_value.len() != size {
+            return Err(Error::InvalidArgument(format!(
+                "{name} is {size} bytes but {} bytes were given",
+                new_value.len()
+            )));
+        }
+        let mut value = vec![0u8; map.def.value_size as usize];
+        unsafe {
+            syscalls_wrapper::bpf_map_lookup_elem(fd, &0u32, value.as_mut_slice())?;
+            value
+                .get_mut(offset..offset + size)
+                .with_context(|| format!("{name} out of section bounds"))?
+                .copy_from_slice(new_value);
+            syscalls_wrapper::bpf_map_update_elem(
+                fd,
+                &0u32,
+                value.as_slice(),
+                BpfMapUpdateFlag::Any,
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn map(&self, name: &str) -> Option<&Map> {
+        self.maps.iter().find(|map| map.name == name)
+    }
+
+    pub fn maps(&self) -> impl Iterator<Item = &Map> {
+        self.maps.iter()
+    }
+
+    pub fn program(&self, name: &str) -> Option<&Program> {
+        self.programs.iter().find(|prog| prog.name == name)
+    }
+
+    pub fn programs(&self) -> impl Iterator<Item = &Program> {
+        self.programs.iter()
+    }
+
+    /// Renders the verifier log of a program that failed to load (the `log` of
+    /// [`Error::VerifierRejected`]) with the C source lines from the object's `.BTF.ext`.
+    pub fn annotate_verifier_log(&self, program: &str, log: &str) -> Result<String> {
+        let prog = self
+            .program(program)
+            .ok_or_else(|| Error::InvalidArgument(format!("No program named {program}")))?;
+        let log = VerifierLog::parse(log);
+        match self.elf.get_section_body(".BTF") {
+            Some(btf) => log.annotate(&btf_parser::parse_btf(btf, 0)?, &prog.line_info),
+            None => Ok(log.to_string()),
+        }
+    }
+
+    pub fn load(&mut self) -> Result<()> {
+        let prog_btf_bin = self.elf.get_section_body(".BTF");
+        let prog_btf_ext_bin = self.elf.get_section_body(".BTF.ext");
+        let (prog_btf, prog_btf_ext) = match (prog_btf_bin, prog_btf_ext_bin) {
+            (Some(btf), Some(btf_ext)) => (
+                Some(btf_parser::parse_btf(btf, 0)?),
+                Some(btf_parser::parse_btf_ext(btf_ext, 0)?),
+            ),
+            (Some(btf), None) => (Some(btf_parser::parse_btf(btf, 0)?), None),
+            _ => (None, None),
+        };
+        let needs_core = prog_btf_ext
+            .as_ref()
+            .is_some_and(|ext| !ext.core_relo_part.is_empty());
+        let kernel_btf = if needs_core {
+            Some(self.btf_source.read()?)
+        } else {
+            None
+        };
+        let vmlinux_btf = match &kernel_btf {
+            Some(kernel_btf) => Some(btf_parser::parse_btf(&kernel_btf.vmlinux, 0)?),
+            None => None,
+        };
+        // shared by every section, so that name indexes and module BTF are built only once
+        let core_targets = vmlinux_btf
+            .as_ref()
+            .map(|vmlinux_btf| CoreTargets::new(vmlinux_btf, kernel_btf.as_ref()));
+
+        if self.btf_fd.is_none()
+            && let (Some(raw), Some(prog_btf)) = (prog_btf_bin, &prog_btf)
+        {
+            let features = *self.btf_features.get_or_insert_with(BtfFeatures::probe);
+            let btf = fixup_datasecs(&self.elf, prog_btf, raw)?;
+            let btf = btf_sanitize::sanitize_btf(prog_btf, &btf, &features)?;
+            // the log of a successful load can overflow the buffer, so like libbpf only ask for
+            // it when retrying a rejected load
+            let result = unsafe { syscalls_wrapper::bpf_btf_load(&btf, &mut Vec::new(), 0) }
+                .or_else(|_| {
+                    let mut log_buf = vec![0; self.log_size];
+                    unsafe {
+                        syscalls_wrapper::bpf_btf_load(&btf, &mut log_buf, self.log_level.max(1))
+                    }
+                });
+            // like libbpf, carry on without BTF when the kernel does not accept it
+            match result {
+                Ok(fd) => {
+                    self.btf_fd = Some(fd);
+                    self.btf_load_error = None;
+                }
+                Err(e) => self.btf_load_error = Some(e),
+            }
+        }
+
+        // maps in `.maps` are only described by the object's BTF, so without it they can only
+        // come from the caller
+        if prog_btf.is_none()
+            && let Some(sym) = self.elf.symbols_in_section(".maps").find(|sym| {
+                sym.sym_type != SymbolType::Section && !self.map_fds.contains_key(&sym.name)
+            })
+        {
+            return Err(Error::Parse(format!(
+                "Map {} is defined in .maps but the object has no .BTF; compile it with -g or \
+                 supply the map with set_map_fd",
+                sym.name
+            )));
+        }
+
+        let mut map_fds = self.map_fds.clone();
+        for map in &mut self.maps {
+            if !map_fds.contains_key(&map.name) {
+                map_fds.insert(map.name.clone(), map.create(self.btf_fd)?);
+            }
+        }
+        let rel_map = self
+            .elf
+            .symbols
+            .iter()
+            .enumerate()
+            .filter_map(|(sym_idx, sym)| {
+                let section_name = self.elf.section_name(sym.section_index)?;
+                let target = if InternalMapKind::from_section_name(section_name).is_some() {
+                    // section symbols (static variables) carry their offset in the addend
+                    let offset = if sym.sym_type == SymbolType::Section {
+                        0
+                    } else {
+                        sym.value as u32
+                    };
+                    RelocationTarget::MapValue {
+                        fd: *map_fds.get(section_name)?,
+                        offset,
+                    }
+                } else {
+                    RelocationTarget::MapFd(*map_fds.get(&sym.name)?)
+                };
+                Some((sym_idx as u32, target))
+            })
+            .collect::<HashMap<_, _>>();
+
+        // relocation and CO-RE offsets are relative to their section, so patch every executable
+        // section once and link programs together from the patched copies
+        let mut sections = HashMap::new();
+        self.poisoned_relocations.clear();
+        for (section_name, shdr) in &self.elf.shdrs {
+            if shdr.sh_type != SHT_PROGBITS
+                || shdr.sh_flags & SHF_EXECINSTR == 0
+                || shdr.sh_size == 0
+            {
+                continue;
+            }
+            let mut insns = self
+                .elf
+                .get_section_body(section_name)
+                .ok_or_else(|| Error::SectionMissing(section_name.clone()))?
+                .to_vec();
+            if let Some(rel_section) = self
+                .elf
+                .parse_relocation_section(&format!(".rel{section_name}"))
+            {
+                elf::relocate(&self.elf, &mut insns, &rel_section, &rel_map)?;
+            }
+            if let (Some(core_targets), Some(prog_btf), Some(prog_btf_ext)) =
+                (&core_targets, &prog_btf, &prog_btf_ext)
+            {
+                let poisoned = elf::core_relocate(
+                    &mut insns,
+                    section_name,
+                    core_targets,
+                    prog_btf,
+                    prog_btf_ext,
+                )?;
+                self.poisoned_relocations.extend(poisoned);
+            }
+            sections.insert(section_name.clone(), insns);
+        }
+
+        for prog in &mut self.programs {
+            if prog.fd.is_some() {
+                continue;
+            }
+            let (insns, segments) = self.elf.link_program(
+                &sections,
+                &prog.section_name,
+                prog.section_offset,
+                prog.insns.len(),
+            )?;
+            let (func_info, line_info) = match (&prog_btf, &prog_btf_ext) {
+                (Some(prog_btf), Some(prog_btf_ext)) => {
+                    program_ext_info(prog_btf, prog_btf_ext, &segments)?
+                }
+                _ => (Vec::new(), Vec::new()),
+            };
+            prog.func_info = func_info;
+            prog.line_info = line_info;
+
+            // the records refer to functions in the object's BTF, so they need it loaded
+            // with FUNC types intact
+            let (func_info, line_info): (&[_], &[_]) = match (self.btf_fd, self.btf_features) {
+                (Some(_), Some(features)) if features.func => (&prog.func_info, &prog.line_info),
+                _ => (&[], &[]),
+            };
+            let opts = BpfProgLoadOpts {
+                prog_name: Some(&prog.name),
+                kern_version: self.kern_version,
+                expected_attach_type: prog.expected_attach_type,
+                prog_btf_fd: self.btf_fd.filter(|_| !func_info.is_empty()),
+                func_info,
+                line_info,
+            };
+            let load = |log_buf: &mut Vec<u8>, log_level: u32| unsafe {
+                syscalls_wrapper::bpf_prog_load_opts(
+                    prog.prog_type,
+                    &insns,
+                    &self.license,
+                    log_buf,
+                    log_level,
+                    &opts,
+                )
+            };
+            // the log of a large program overflows the buffer even when it verifies, so like
+            // the BTF above only ask for it when retrying a rejected load
+            let fd = match load(&mut Vec::new(), 0) {
+                Ok(fd) => fd,
+                Err(_) => load(&mut vec![0; self.log_size], self.log_level.max(1))?,
+            };
+            prog.fd = Some(fd as i32);
+        }
+        Ok(())
+    }
+}
+
+/// Fills in what the compiler leaves to the loader in the object's BTF: the sizes of the
+/// DATASECs and the offsets of their variables, which are only known from the ELF sections and
+/// symbols. Returns a patched copy of `raw`.
+fn fixup_datasecs(elf: &Elf, btf: &Btf, raw: &[u8]) -> Result<Vec<u8>> {
+    let mut data = raw.to_vec();
+    let mut write_u32 = |pos: usize, value: u32| -> Result<()> {
+        data.get_mut(pos..pos + 4)
+            .context("BTF type out of bounds")?
+            .copy_from_slice(&value.to_le_bytes());
+        Ok(())
+    };
+    let mut pos = (btf.header.hdr_len + btf.header.type_off) as usize;
+    // skip the void type that the parser prepends
+    for (_, btf_type) in btf.types().skip(1) {
+        if let BtfTypeDetail::DataSec(vars) = &btf_type.detail
+            && let section_name = btf.name_of(btf_type)?
+            && let Some(shdr) = elf.shdrs.get(section_name)
+        {
+            write_u32(pos + 8, shdr.sh_size as u32)?;
+            for (idx, var_secinfo) in vars.iter().enumerate() {
+                let var = btf.get_type(var_secinfo.type_id)?;
+                let var_name = btf.name_of(var)?;
+                if let Some(sym) = elf
+                    .symbols_in_section(section_name)
+                    .find(|sym| sym.name == var_name)
+                {
+                    let secinfo_pos = pos + 12 + idx * size_of::<BtfVarSecinfo>();
+                    write_u32(secinfo_pos + 4, sym.value as u32)?;
+                }
+            }
+        }
+        pos += btf_type.encoded_len();
+    }
+    Ok(data)
+}
+
+/// Collects the func_info and line_info records of every function linked into a program and
+/// converts their byte offsets within the ELF sections into instruction indices within the
+/// program.
+fn program_ext_info(
+    prog_btf: &Btf,
+    prog_btf_ext: &BtfExt,
+    segments: &[LinkSegment],
+) -> Result<(Vec<BpfFuncInfo>, Vec<BpfLineInfo>)> {
+    let mut func_info = Vec::new();
+    let mut line_info = Vec::new();
+    for segment in segments {
+        let range = segment.section_offset..segment.section_offset + segment.size;
+        let to_insn_idx = |insn_off: u32| {
+            ((insn_off as usize - segment.section_offset + segment.insn_offset) / BPF_INSN_SIZE)
+                as u32
+        };
+        for rec in prog_btf_ext.func_info(prog_btf, segment.section_name)? {
+            if range.contains(&(rec.insn_off as usize)) {
+                func_info.push(BpfFuncInfo {
+                    insn_off: to_insn_idx(rec.insn_off),
+                    ..rec.clone()
+                });
+            }
+        }
+        for rec in prog_btf_ext.line_info(prog_btf, segment.section_name)? {
+            if range.contains(&(rec.insn_off as usize)) {
+                line_info.push(BpfLineInfo {
+                    insn_off: to_insn_idx(rec.insn_off),
+                    ..rec.clone()
+                });
+            }
+        }
+    }
+    Ok((func_info, line_info))
+}
+
+impl Drop for Object {
+    fn drop(&mut self) {
+        for prog in &self.programs {
+            if let Some(fd) = prog.fd {
+                let _ = unsafe { syscalls_wrapper::close(fd) };
+            }
+        }
+        for map in &self.maps {
+            if let Some(fd) = map.fd {
+                let _ = unsafe { syscalls_wrapper::close(fd) };
+            }
+        }
+        if let Some(fd) = self.btf_fd {
+            let _ = unsafe { syscalls_wrapper::close(fd) };
+        }
+    }
+}
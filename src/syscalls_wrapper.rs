@@ -1,3 +1,8 @@
+use crate::{
+    btf::{BpfFuncInfo, BpfLineInfo},
+    error::{Error, Result},
+};
+
 #[repr(C)]
 #[derive(Clone, Copy)]
 struct BpfMapCreateAttr {
@@ -5,15 +10,102 @@ struct BpfMapCreateAttr {
     key_size: u32,
     value_size: u32,
     max_entries: u32,
+    map_flags: u32,
+    inner_map_fd: u32,
+    numa_node: u32,
+    map_name: [u8; 16],
+    map_ifindex: u32,
+    btf_fd: u32,
+    btf_key_type_id: u32,
+    btf_value_type_id: u32,
+    btf_vmlinux_value_type_id: u32,
+    map_extra: u64,
 }
 
 #[repr(C)]
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BpfMapType {
     Unspec, /* Reserve 0 as invalid map type */
     Hash,
     Array,
     ProgArray,
+    PerfEventArray,
+    PercpuHash,
+    PercpuArray,
+    StackTrace,
+    CgroupArray,
+    LruHash,
+    LruPercpuHash,
+    LpmTrie,
+    ArrayOfMaps,
+    HashOfMaps,
+    Devmap,
+    Sockmap,
+    Cpumap,
+    Xskmap,
+    Sockhash,
+    CgroupStorage,
+    ReuseportSockarray,
+    PercpuCgroupStorage,
+    Queue,
+    Stack,
+    SkStorage,
+    DevmapHash,
+    StructOps,
+    Ringbuf,
+    InodeStorage,
+    TaskStorage,
+    BloomFilter,
+    UserRingbuf,
+    CgrpStorage,
+    Arena,
+}
+
+impl TryFrom<u32> for BpfMapType {
+    type Error = Error;
+
+    fn try_from(value: u32) -> Result<Self> {
+        const MAP_TYPES: [BpfMapType; 34] = [
+            BpfMapType::Unspec,
+            BpfMapType::Hash,
+            BpfMapType::Array,
+            BpfMapType::ProgArray,
+            BpfMapType::PerfEventArray,
+            BpfMapType::PercpuHash,
+            BpfMapType::PercpuArray,
+            BpfMapType::StackTrace,
+            BpfMapType::CgroupArray,
+            BpfMapType::LruHash,
+            BpfMapType::LruPercpuHash,
+            BpfMapType::LpmTrie,
+            BpfMapType::ArrayOfMaps,
+            BpfMapType::HashOfMaps,
+            BpfMapType::Devmap,
+            BpfMapType::Sockmap,
+            BpfMapType::Cpumap,
+            BpfMapType::Xskmap,
+            BpfMapType::Sockhash,
+            BpfMapType::CgroupStorage,
+            BpfMapType::ReuseportSockarray,
+            BpfMapType::PercpuCgroupStorage,
+            BpfMapType::Queue,
+            BpfMapType::Stack,
+            BpfMapType::SkStorage,
+            BpfMapType::DevmapHash,
+            BpfMapType::StructOps,
+            BpfMapType::Ringbuf,
+            BpfMapType::InodeStorage,
+            BpfMapType::TaskStorage,
+            BpfMapType::BloomFilter,
+            BpfMapType::UserRingbuf,
+            BpfMapType::CgrpStorage,
+            BpfMapType::Arena,
+        ];
+        MAP_TYPES
+            .get(value as usize)
+            .copied()
+            .ok_or_else(|| Error::Parse(format!("Invalid BPF map type: {}", value)))
+    }
 }
 
 #[repr(C)]
@@ -42,11 +134,16 @@ pub union ValueOrNextKey {
 
 #[repr(C)]
 #[derive(Clone, Copy)]
-struct BpfMapGetNextKeyAttr {
+struct BpfMapFreezeAttr {
     map_fd: u32,
-    key: u64,
-    next_key: u64,
-    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct BpfObjAttr {
+    pathname: u64,
+    bpf_fd: u32,
+    file_flags: u32,
 }
 
 #[repr(C)]
@@ -59,11 +156,35 @@ struct BpfProgLoadAttr {
     log_level: u32,
     log_size: u32,
     log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+    prog_name: [u8; 16],
+    prog_ifindex: u32,
+    expected_attach_type: u32,
+    prog_btf_fd: u32,
+    func_info_rec_size: u32,
+    func_info: u64,
+    func_info_cnt: u32,
+    line_info_rec_size: u32,
+    line_info: u64,
+    line_info_cnt: u32,
+    attach_btf_id: u32,
 }
 
 #[repr(C)]
 #[derive(Clone, Copy)]
-enum BpfAttachType {
+struct BpfBtfLoadAttr {
+    btf: u64,
+    btf_log_buf: u64,
+    btf_size: u32,
+    btf_log_size: u32,
+    btf_log_level: u32,
+    btf_log_true_size: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BpfAttachType {
     CgroupInetIngress,
     CgroupInetEgress,
     CgroupInetSockCreate,
@@ -131,13 +252,6 @@ union Target {
     target_ifindex: u32,
 }
 
-#[repr(C)]
-#[derive(Clone, Copy)]
-union Relative {
-    relative_fd: u32,
-    relative_id: u32,
-}
-
 #[repr(C)]
 #[derive(Clone, Copy)]
 struct BpfLinkCreateAttr {
@@ -151,11 +265,14 @@ struct BpfLinkCreateAttr {
 union BpfAttr {
     map_create: BpfMapCreateAttr,
     map_elem: BpfMapElemAttr,
+    map_freeze: BpfMapFreezeAttr,
+    obj: BpfObjAttr,
     prog_load: BpfProgLoadAttr,
     link_create: BpfLinkCreateAttr,
+    btf_load: BpfBtfLoadAttr,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BpfProgType {
     Unspec, /* Reserve 0 as invalid
             program type */
@@ -184,61 +301,125 @@ pub enum BpfProgType {
     /* See /usr/include/linux/bpf.h for the full list. */
 }
 
+/// The `bpf(2)` commands the loader issues, numbered as in `enum bpf_cmd` of linux/bpf.h.
 #[repr(C)]
 enum BpfCmd {
-    MapCreate,
-    MapLookupElem,
-    MapUpdateElem,
-    MapDeleteElem,
-    MapGetNextKey,
-    ProgLoad,
-    ObjPin,
-    ObjGet,
-    ProgAttach,
-    ProgDetach,
-    ProgTestRun,
-    ProgGetNextId,
-    MapGetNextId,
-    ProgGetFdById,
-    MapGetFdById,
-    ObjGetInfoByFd,
-    ProgQuery,
-    RawTracepointOpen,
-    BtfLoad,
-    BtfGetFdById,
-    TaskFdQuery,
-    MapLookupAndDeleteElem,
-    MapFreeze,
-    BtfGetNextId,
-    MapLookupBatch,
-    MapLookupAndDeleteBatch,
-    MapUpdateBatch,
-    MapDeleteBatch,
-    LinkCreate,
-    LinkUpdate,
-}
-
-fn handle_error(ret: i64) -> Result<i64, std::io::Error> {
+    MapCreate = 0,
+    MapLookupElem = 1,
+    MapUpdateElem = 2,
+    ProgLoad = 5,
+    ObjPin = 6,
+    ObjGet = 7,
+    BtfLoad = 18,
+    MapFreeze = 22,
+    LinkCreate = 28,
+}
+
+impl BpfCmd {
+    fn name(&self) -> &'static str {
+        match self {
+            BpfCmd::MapCreate => "BPF_MAP_CREATE",
+            BpfCmd::MapLookupElem => "BPF_MAP_LOOKUP_ELEM",
+            BpfCmd::MapUpdateElem => "BPF_MAP_UPDATE_ELEM",
+            BpfCmd::ProgLoad => "BPF_PROG_LOAD",
+            BpfCmd::ObjPin => "BPF_OBJ_PIN",
+            BpfCmd::ObjGet => "BPF_OBJ_GET",
+            BpfCmd::BtfLoad => "BPF_BTF_LOAD",
+            BpfCmd::MapFreeze => "BPF_MAP_FREEZE",
+            BpfCmd::LinkCreate => "BPF_LINK_CREATE",
+        }
+    }
+}
+
+fn handle_error(ret: i64, call: &'static str) -> Result<i64> {
     if ret == -1 {
-        Err(std::io::Error::last_os_error())
+        Err(Error::Syscall {
+            call,
+            source: std::io::Error::last_os_error(),
+        })
     } else {
         Ok(ret)
     }
 }
 
-unsafe fn bpf(cmd: i32, attr: &BpfAttr, size: usize) -> Result<usize, std::io::Error> {
-    let ret = unsafe { libc::syscall(libc::SYS_bpf, cmd, attr, size) };
-    Ok(handle_error(ret)? as usize)
+/// Encodes a program or map name for the kernel, which keeps at most 15 bytes plus the
+/// terminating NUL and only accepts alphanumerics, '_' and '.'.
+fn object_name(name: Option<&str>) -> [u8; 16] {
+    let mut object_name = [0u8; 16];
+    if let Some(name) = name {
+        let name = name
+            .bytes()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == b'_' || *c == b'.')
+            .take(object_name.len() - 1)
+            .collect::<Vec<_>>();
+        object_name[..name.len()].copy_from_slice(&name);
+    }
+    object_name
+}
+
+fn to_c_string(s: &str) -> Result<std::ffi::CString> {
+    std::ffi::CString::new(s).map_err(|e| Error::InvalidArgument(e.to_string()))
 }
 
+unsafe fn bpf(cmd: BpfCmd, attr: &BpfAttr, size: usize) -> Result<usize> {
+    let name = cmd.name();
+    let ret = unsafe { libc::syscall(libc::SYS_bpf, cmd as i32, attr, size) };
+    Ok(handle_error(ret, name)? as usize)
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BpfProgLoadOpts<'a> {
+    pub prog_name: Option<&'a str>,
+    pub kern_version: u32,
+    pub expected_attach_type: Option<BpfAttachType>,
+    /// BTF that `func_info` and `line_info` refer to, as returned by [`bpf_btf_load`].
+    pub prog_btf_fd: Option<i32>,
+    /// One record per function, with `insn_off` counted in instructions.
+    pub func_info: &'a [BpfFuncInfo],
+    /// Source line records, with `insn_off` counted in instructions.
+    pub line_info: &'a [BpfLineInfo],
+}
+
+/// # Safety
+/// `insns` must hold a complete eBPF instruction stream; any map fds embedded in
+/// it must stay open until the kernel has finished loading the program.
 pub unsafe fn bpf_prog_load(
     prog_type: BpfProgType,
     insns: &[u8],
     license: &str,
     log_buf: &mut Vec<u8>,
     log_level: u32,
-) -> Result<usize, std::io::Error> {
-    let log_size = log_buf.len() as u32;
+) -> Result<usize> {
+    unsafe {
+        bpf_prog_load_opts(
+            prog_type,
+            insns,
+            license,
+            log_buf,
+            log_level,
+            &BpfProgLoadOpts::default(),
+        )
+    }
+}
+
+/// # Safety
+/// Same requirements as [`bpf_prog_load`].
+pub unsafe fn bpf_prog_load_opts(
+    prog_type: BpfProgType,
+    insns: &[u8],
+    license: &str,
+    log_buf: &mut Vec<u8>,
+    log_level: u32,
+    opts: &BpfProgLoadOpts,
+) -> Result<usize> {
+    let license = to_c_string(license)?;
+    let prog_name = object_name(opts.prog_name);
+    // the kernel rejects a log buffer without a log level and vice versa
+    let (log_ptr, log_size) = if log_level == 0 || log_buf.is_empty() {
+        (0, 0)
+    } else {
+        (log_buf.as_mut_ptr() as u64, log_buf.len() as u32)
+    };
     let insn_cnt = insns.len() as u32 / std::mem::size_of::<u64>() as u32;
     let attr = BpfAttr {
         prog_load: BpfProgLoadAttr {
@@ -246,35 +427,153 @@ pub unsafe fn bpf_prog_load(
             insns: insns.as_ptr() as u64,
             insn_cnt,
             license: license.as_ptr() as u64,
-            log_buf: log_buf.as_mut_ptr() as u64,
+            log_buf: log_ptr,
             log_size,
-            log_level,
+            log_level: if log_size == 0 { 0 } else { log_level },
+            kern_version: opts.kern_version,
+            prog_flags: 0,
+            prog_name,
+            prog_ifindex: 0,
+            expected_attach_type: opts.expected_attach_type.map_or(0, |t| t as u32),
+            prog_btf_fd: opts.prog_btf_fd.unwrap_or(0) as u32,
+            func_info_rec_size: std::mem::size_of::<BpfFuncInfo>() as u32,
+            func_info: opts.func_info.as_ptr() as u64,
+            func_info_cnt: opts.func_info.len() as u32,
+            line_info_rec_size: std::mem::size_of::<BpfLineInfo>() as u32,
+            line_info: opts.line_info.as_ptr() as u64,
+            line_info_cnt: opts.line_info.len() as u32,
+            attach_btf_id: 0,
         },
     };
-    unsafe {
+    let result = unsafe {
         bpf(
-            BpfCmd::ProgLoad as i32,
+            BpfCmd::ProgLoad,
             &attr,
             std::mem::size_of::<BpfProgLoadAttr>(),
         )
+    };
+    result.map_err(|e| match e {
+        Error::Syscall { source, .. } => Error::VerifierRejected {
+            program: opts.prog_name.unwrap_or_default().to_string(),
+            source,
+            log: log_to_string(log_buf),
+        },
+        e => e,
+    })
+}
+
+/// Returns the NUL-terminated text the kernel wrote into a log buffer.
+fn log_to_string(log_buf: &[u8]) -> String {
+    let len = log_buf
+        .iter()
+        .position(|&c| c == 0)
+        .unwrap_or(log_buf.len());
+    String::from_utf8_lossy(&log_buf[..len]).into_owned()
+}
+
+/// Loads raw BTF into the kernel. On failure the kernel's BTF verifier log is appended to the
+/// error.
+///
+/// # Safety
+/// The returned fd is owned by the caller and must be closed with [`close`].
+pub unsafe fn bpf_btf_load(btf: &[u8], log_buf: &mut Vec<u8>, log_level: u32) -> Result<i32> {
+    // the kernel rejects a log buffer without a log level and vice versa
+    let (log_ptr, log_size) = if log_level == 0 || log_buf.is_empty() {
+        (0, 0)
+    } else {
+        (log_buf.as_mut_ptr() as u64, log_buf.len() as u32)
+    };
+    let attr = BpfAttr {
+        btf_load: BpfBtfLoadAttr {
+            btf: btf.as_ptr() as u64,
+            btf_log_buf: log_ptr,
+            btf_size: btf.len() as u32,
+            btf_log_size: log_size,
+            btf_log_level: if log_size == 0 { 0 } else { log_level },
+            btf_log_true_size: 0,
+        },
+    };
+    let result = unsafe {
+        bpf(
+            BpfCmd::BtfLoad,
+            &attr,
+            std::mem::size_of::<BpfBtfLoadAttr>(),
+        )
+    };
+    match result {
+        Ok(fd) => Ok(fd as i32),
+        Err(Error::Syscall { call, source }) if log_size != 0 => Err(Error::Syscall {
+            call,
+            source: std::io::Error::new(
+                source.kind(),
+                format!("{source}\n{}", log_to_string(log_buf)),
+            ),
+        }),
+        Err(e) => Err(e),
     }
 }
 
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BpfMapCreateOpts<'a> {
+    pub map_name: Option<&'a str>,
+    pub map_flags: u32,
+    pub inner_map_fd: Option<i32>,
+    pub numa_node: u32,
+    pub map_extra: u64,
+    /// BTF describing the key and value, as returned by [`bpf_btf_load`].
+    pub btf_fd: Option<i32>,
+    pub btf_key_type_id: u32,
+    pub btf_value_type_id: u32,
+}
+
+/// # Safety
+/// The returned fd is owned by the caller and must be closed with [`close`].
 pub unsafe fn bpf_map_create(
     map_type: BpfMapType,
     key_size: u32,
     value_size: u32,
     map_entries: u32,
-) -> Result<i32, std::io::Error> {
+) -> Result<i32> {
+    unsafe {
+        bpf_map_create_opts(
+            map_type,
+            key_size,
+            value_size,
+            map_entries,
+            &BpfMapCreateOpts::default(),
+        )
+    }
+}
+
+/// # Safety
+/// Same requirements as [`bpf_map_create`].
+pub unsafe fn bpf_map_create_opts(
+    map_type: BpfMapType,
+    key_size: u32,
+    value_size: u32,
+    map_entries: u32,
+    opts: &BpfMapCreateOpts,
+) -> Result<i32> {
+    let map_name = object_name(opts.map_name);
     let attr = BpfMapCreateAttr {
         map_type: map_type as u32,
         key_size,
         value_size,
         max_entries: map_entries,
+        map_flags: opts.map_flags,
+        inner_map_fd: opts.inner_map_fd.unwrap_or(0) as u32,
+        numa_node: opts.numa_node,
+        map_name,
+        map_ifindex: 0,
+        btf_fd: opts.btf_fd.unwrap_or(0) as u32,
+        btf_key_type_id: opts.btf_key_type_id,
+        btf_value_type_id: opts.btf_value_type_id,
+        btf_vmlinux_value_type_id: 0,
+        map_extra: opts.map_extra,
     };
     let ret = unsafe {
         bpf(
-            BpfCmd::MapCreate as i32,
+            BpfCmd::MapCreate,
             &BpfAttr { map_create: attr },
             std::mem::size_of::<BpfMapCreateAttr>(),
         )?
@@ -282,11 +581,51 @@ pub unsafe fn bpf_map_create(
     Ok(ret as i32)
 }
 
-pub unsafe fn bpf_map_lookup_elem<T, U>(
+/// # Safety
+/// `fd` must refer to a BPF map, program or link.
+pub unsafe fn bpf_obj_pin(fd: i32, path: &str) -> Result<i32> {
+    let path = to_c_string(path)?;
+    let attr = BpfObjAttr {
+        pathname: path.as_ptr() as u64,
+        bpf_fd: fd as u32,
+        file_flags: 0,
+    };
+    let ret = unsafe {
+        bpf(
+            BpfCmd::ObjPin,
+            &BpfAttr { obj: attr },
+            std::mem::size_of::<BpfObjAttr>(),
+        )?
+    };
+    Ok(ret as i32)
+}
+
+/// # Safety
+/// The returned fd is owned by the caller and must be closed with [`close`].
+pub unsafe fn bpf_obj_get(path: &str) -> Result<i32> {
+    let path = to_c_string(path)?;
+    let attr = BpfObjAttr {
+        pathname: path.as_ptr() as u64,
+        bpf_fd: 0,
+        file_flags: 0,
+    };
+    let ret = unsafe {
+        bpf(
+            BpfCmd::ObjGet,
+            &BpfAttr { obj: attr },
+            std::mem::size_of::<BpfObjAttr>(),
+        )?
+    };
+    Ok(ret as i32)
+}
+
+/// # Safety
+/// `T` and `U` must match the key and value sizes the map was created with.
+pub unsafe fn bpf_map_lookup_elem<T: ?Sized, U: ?Sized>(
     map_fd: i32,
     key: &T,
     value: &mut U,
-) -> Result<i32, std::io::Error> {
+) -> Result<i32> {
     let attr = BpfMapElemAttr {
         map_fd: map_fd as u32,
         key: key as *const T as *const libc::c_void as u64,
@@ -297,7 +636,7 @@ pub unsafe fn bpf_map_lookup_elem<T, U>(
     };
     let ret = unsafe {
         bpf(
-            BpfCmd::MapLookupElem as i32,
+            BpfCmd::MapLookupElem,
             &BpfAttr { map_elem: attr },
             std::mem::size_of::<BpfMapElemAttr>(),
         )?
@@ -305,12 +644,14 @@ pub unsafe fn bpf_map_lookup_elem<T, U>(
     Ok(ret as i32)
 }
 
-pub unsafe fn bpf_map_update_elem<T, U>(
+/// # Safety
+/// `T` and `U` must match the key and value sizes the map was created with.
+pub unsafe fn bpf_map_update_elem<T: ?Sized, U: ?Sized>(
     map_fd: i32,
     key: &T,
     value: &U,
     flags: BpfMapUpdateFlag,
-) -> Result<i32, std::io::Error> {
+) -> Result<i32> {
     let attr = BpfMapElemAttr {
         map_fd: map_fd as u32,
         key: key as *const T as *const libc::c_void as u64,
@@ -321,7 +662,7 @@ pub unsafe fn bpf_map_update_elem<T, U>(
     };
     let ret = unsafe {
         bpf(
-            BpfCmd::MapUpdateElem as i32,
+            BpfCmd::MapUpdateElem,
             &BpfAttr { map_elem: attr },
             std::mem::size_of::<BpfMapElemAttr>(),
         )?
@@ -329,13 +670,44 @@ pub unsafe fn bpf_map_update_elem<T, U>(
     Ok(ret as i32)
 }
 
-pub unsafe fn close(fd: i32) -> Result<i32, std::io::Error> {
+/// Makes the map read-only for user space; programs loaded afterwards may still read it.
+///
+/// # Safety
+/// `map_fd` must refer to a BPF map.
+pub unsafe fn bpf_map_freeze(map_fd: i32) -> Result<i32> {
+    let attr = BpfMapFreezeAttr {
+        map_fd: map_fd as u32,
+    };
+    let ret = unsafe {
+        bpf(
+            BpfCmd::MapFreeze,
+            &BpfAttr { map_freeze: attr },
+            std::mem::size_of::<BpfMapFreezeAttr>(),
+        )?
+    };
+    Ok(ret as i32)
+}
+
+/// # Safety
+/// `fd` must be an open file descriptor that is not used after this call.
+pub unsafe fn close(fd: i32) -> Result<i32> {
     let ret = unsafe { libc::close(fd) };
-    Ok(handle_error(ret as i64)? as i32)
+    Ok(handle_error(ret as i64, "close")? as i32)
 }
 
-pub unsafe fn xdp_attach(ifindex: i32, prog_fd: i32) -> Result<i32, std::io::Error> {
-    let mut attr = BpfAttr {
+/// Returns the release of the running kernel, as printed by `uname -r`.
+pub fn uname_release() -> Result<String> {
+    let mut uts = unsafe { std::mem::zeroed::<libc::utsname>() };
+    let ret = unsafe { libc::uname(&mut uts) };
+    handle_error(ret as i64, "uname")?;
+    let release = unsafe { std::ffi::CStr::from_ptr(uts.release.as_ptr()) };
+    Ok(release.to_str()?.to_string())
+}
+
+/// # Safety
+/// `prog_fd` must refer to a loaded XDP program.
+pub unsafe fn xdp_attach(ifindex: i32, prog_fd: i32) -> Result<i32> {
+    let attr = BpfAttr {
         link_create: BpfLinkCreateAttr {
             fd: prog_fd as u32,
             target: Target {
@@ -347,7 +719,7 @@ pub unsafe fn xdp_attach(ifindex: i32, prog_fd: i32) -> Result<i32, std::io::Err
     };
     let ret = unsafe {
         bpf(
-            BpfCmd::LinkCreate as i32,
+            BpfCmd::LinkCreate,
             &attr,
             std::mem::size_of::<BpfLinkCreateAttr>(),
         )?
@@ -355,7 +727,9 @@ pub unsafe fn xdp_attach(ifindex: i32, prog_fd: i32) -> Result<i32, std::io::Err
     Ok(ret as i32)
 }
 
-pub unsafe fn open_raw_sock(ifindex: i32) -> Result<i32, std::io::Error> {
+/// # Safety
+/// The returned socket fd is owned by the caller and must be closed with [`close`].
+pub unsafe fn open_raw_sock(ifindex: i32) -> Result<i32> {
     let socket_fd = unsafe {
         libc::socket(
             libc::AF_PACKET,
@@ -364,7 +738,10 @@ pub unsafe fn open_raw_sock(ifindex: i32) -> Result<i32, std::io::Error> {
         )
     };
     if socket_fd < 0 {
-        return Err(std::io::Error::last_os_error());
+        return Err(Error::Syscall {
+            call: "socket",
+            source: std::io::Error::last_os_error(),
+        });
     }
     let mut sll: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
     sll.sll_family = libc::AF_PACKET as u16;
@@ -378,18 +755,14 @@ pub unsafe fn open_raw_sock(ifindex: i32) -> Result<i32, std::io::Error> {
             std::mem::size_of::<libc::sockaddr_ll>() as u32,
         ) < 0
     } {
-        return Err(std::io::Error::last_os_error());
+        return Err(Error::Syscall {
+            call: "bind",
+            source: std::io::Error::last_os_error(),
+        });
     }
     Ok(socket_fd)
 }
 
-const PERF_EVENT_IOC_SET_BPF: u32 = libc::_IOW::<u32>('$' as u32, 8);
-
-unsafe fn perf_event_open(attr: &PerfEventAttr) -> Result<i32, std::io::Error> {
-    let ret = unsafe { libc::syscall(libc::SYS_perf_event_open, attr, -1, 0, -1, 0) };
-    Ok(handle_error(ret)? as i32)
-}
-
 impl Default for PerfEventAttr {
     fn default() -> Self {
         PerfEventAttr {
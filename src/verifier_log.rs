@@ -0,0 +1,396 @@
+use std::fmt::{self, Write as _};
+
+use crate::{
+    btf::{BpfLineInfo, Btf},
+    error::Result,
+};
+
+/// Register and stack slot values as printed by the verifier, e.g.
+/// `frame1: R1=ctx() R10=fp0 fp-8=mmmm????`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RegState {
+    /// Call frame of a subprogram; `None` for the main program.
+    pub frame: Option<u32>,
+    /// `(name, value)` pairs such as `("R1", "ctx()")`. Liveness marks stay part of the
+    /// name (`R0_w`).
+    pub regs: Vec<(String, String)>,
+}
+
+impl RegState {
+    /// Parses a state, returning `None` for text that is not made of `name=value` pairs.
+    fn parse(text: &str) -> Option<RegState> {
+        let mut tokens = split_top_level(text.trim());
+        let mut state = RegState::default();
+        if let Some(frame) = tokens
+            .first()
+            .and_then(|token| token.strip_prefix("frame")?.strip_suffix(':'))
+        {
+            state.frame = Some(frame.parse().ok()?);
+            tokens.remove(0);
+        }
+        if tokens.is_empty() {
+            return None;
+        }
+        for token in tokens {
+            let (name, value) = token.split_once('=')?;
+            state.regs.push((name.to_string(), value.to_string()));
+        }
+        Some(state)
+    }
+
+    /// Value of a register or stack slot, ignoring liveness marks (`R0` matches `R0_w`).
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.regs.iter().find_map(|(reg, value)| {
+            let base = reg.split_once('_').map_or(reg.as_str(), |(base, _)| base);
+            (reg == name || base == name).then_some(value.as_str())
+        })
+    }
+}
+
+impl fmt::Display for RegState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(frame) = self.frame {
+            write!(f, "frame{frame}:")?;
+        }
+        for (idx, (name, value)) in self.regs.iter().enumerate() {
+            if idx > 0 || self.frame.is_some() {
+                f.write_char(' ')?;
+            }
+            write!(f, "{name}={value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Splits on spaces outside of parentheses, which group values such as
+/// `scalar(smin=0,var_off=(0x0; 0xff))`.
+fn split_top_level(text: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut depth = 0usize;
+    let mut start = 0;
+    for (idx, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            ' ' if depth == 0 => {
+                if idx > start {
+                    tokens.push(&text[start..idx]);
+                }
+                start = idx + 1;
+            }
+            _ => {}
+        }
+    }
+    if start < text.len() {
+        tokens.push(&text[start..]);
+    }
+    tokens
+}
+
+/// One line of a verifier log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogLine {
+    /// `2: (61) r0 = *(u32 *)(r1 +0)  ; R0_w=scalar()`: an instruction and, from log level 2
+    /// or for instructions that change state, the registers it wrote.
+    Insn {
+        insn_idx: usize,
+        opcode: u8,
+        text: String,
+        state: Option<RegState>,
+    },
+    /// `7: frame1: R1=1 R10=fp0`: the state on reaching an instruction.
+    State { insn_idx: usize, state: RegState },
+    /// `from 9 to 3: R0=2 R10=fp0`: the state of a branch the verifier goes back to.
+    Branch {
+        from: usize,
+        to: usize,
+        state: RegState,
+    },
+    /// `; return x + 1; @ calls.c:1`: printed by kernels that were given line info.
+    Source(String),
+    /// Anything else, such as the rejection reason or the summary.
+    Message(String),
+}
+
+impl LogLine {
+    pub fn parse(line: &str) -> LogLine {
+        if let Some(source) = line.strip_prefix("; ") {
+            return LogLine::Source(source.to_string());
+        }
+        if let Some(rest) = line.strip_prefix("from ")
+            && let Some((from, rest)) = rest.split_once(" to ")
+            && let Some((to, state)) = rest.split_once(": ")
+            && let (Ok(from), Ok(to)) = (from.parse(), to.parse())
+            && let Some(state) = RegState::parse(state)
+        {
+            return LogLine::Branch { from, to, state };
+        }
+        if let Some((idx, rest)) = line.split_once(": ")
+            && let Ok(insn_idx) = idx.parse()
+        {
+            if let Some(insn) = rest.strip_prefix('(')
+                && let Some((opcode, insn)) = insn.split_once(") ")
+                && let Ok(opcode) = u8::from_str_radix(opcode, 16)
+            {
+                let (text, state) = match insn.split_once(';') {
+                    Some((text, state)) => (text, RegState::parse(state)),
+                    None => (insn, None),
+                };
+                return LogLine::Insn {
+                    insn_idx,
+                    opcode,
+                    text: text.trim_end().to_string(),
+                    state,
+                };
+            }
+            if let Some(state) = RegState::parse(rest) {
+                return LogLine::State { insn_idx, state };
+            }
+        }
+        LogLine::Message(line.to_string())
+    }
+}
+
+impl fmt::Display for LogLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogLine::Insn {
+                insn_idx,
+                opcode,
+                text,
+                state,
+            } => {
+                let insn = format!("{insn_idx}: ({opcode:02x}) {text}");
+                match state {
+                    // the kernel lines up the states in a column
+                    Some(state) => write!(f, "{insn:<37} ; {state}"),
+                    None => f.write_str(&insn),
+                }
+            }
+            LogLine::State { insn_idx, state } => write!(f, "{insn_idx}: {state}"),
+            LogLine::Branch { from, to, state } => write!(f, "from {from} to {to}: {state}"),
+            LogLine::Source(source) => write!(f, "; {source}"),
+            LogLine::Message(message) => f.write_str(message),
+        }
+    }
+}
+
+/// A verifier log split into lines, as returned in [`crate::error::Error::VerifierRejected`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifierLog {
+    pub lines: Vec<LogLine>,
+}
+
+impl VerifierLog {
+    pub fn parse(log: &str) -> VerifierLog {
+        VerifierLog {
+            lines: log
+                .trim_end_matches('\0')
+                .lines()
+                .map(LogLine::parse)
+                .collect(),
+        }
+    }
+
+    /// Index of the last instruction the verifier looked at, which for a rejected program is
+    /// the one it rejected.
+    pub fn last_insn(&self) -> Option<usize> {
+        self.lines.iter().rev().find_map(|line| match line {
+            LogLine::Insn { insn_idx, .. } => Some(*insn_idx),
+            _ => None,
+        })
+    }
+
+    /// The messages following the last instruction, without the `processed N insns` summary.
+    pub fn error(&self) -> Option<String> {
+        let start = self
+            .lines
+            .iter()
+            .rposition(|line| matches!(line, LogLine::Insn { .. }))
+            .map_or(0, |idx| idx + 1);
+        let messages = self.lines[start..]
+            .iter()
+            .filter_map(|line| match line {
+                LogLine::Message(message)
+                    if !message.is_empty() && !message.starts_with("processed ") =>
+                {
+                    Some(message.as_str())
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        (!messages.is_empty()).then(|| messages.join("\n"))
+    }
+
+    /// Every state recorded on reaching `insn_idx`, either before it or through a branch.
+    pub fn states(&self, insn_idx: usize) -> impl Iterator<Item = &RegState> {
+        self.lines.iter().filter_map(move |line| match line {
+            LogLine::State {
+                insn_idx: idx,
+                state,
+            } if *idx == insn_idx => Some(state),
+            LogLine::Branch { to, state, .. } if *to == insn_idx => Some(state),
+            _ => None,
+        })
+    }
+
+    /// Renders the log with the C source line of each instruction printed above it, in the
+    /// style of `bpftool prog dump xlated linum`. `line_info` must be sorted, with `insn_off`
+    /// counted in instructions of the program the log belongs to (see
+    /// [`crate::object::Program::line_info`]), and its strings come from `btf`. Source lines
+    /// printed by the kernel are replaced; without line info the log is rendered unchanged.
+    pub fn annotate(&self, btf: &Btf, line_info: &[BpfLineInfo]) -> Result<String> {
+        let mut out = String::new();
+        let mut last_line_info = None;
+        for line in &self.lines {
+            match line {
+                LogLine::Source(_) if !line_info.is_empty() => continue,
+                LogLine::Insn { insn_idx, .. } => {
+                    let idx = line_info.partition_point(|rec| rec.insn_off as usize <= *insn_idx);
+                    if idx > 0 && last_line_info != Some(idx - 1) {
+                        let rec = &line_info[idx - 1];
+                        out.push_str(&format!(
+                            "; {} @ {}:{}:{}\n",
+                            btf.name_by_offset(rec.line_off)?.trim(),
+                            btf.name_by_offset(rec.file_name_off)?,
+                            rec.line(),
+                            rec.column()
+                        ));
+                        last_line_info = Some(idx - 1);
+                    }
+                }
+                // the verifier continues on another path
+                LogLine::Branch { .. } => last_line_info = None,
+                _ => {}
+            }
+            out.push_str(&format!("{line}\n"));
+        }
+        Ok(out)
+    }
+}
+
+impl fmt::Display for VerifierLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in &self.lines {
+            writeln!(f, "{line}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{btf_builder::BtfBuilder, btf_parser};
+
+    const LOG: &str = "\
+func#0 @0
+0: R1=ctx() R10=fp0
+0: (61) r0 = *(u32 *)(r1 +0)          ; R0_w=pkt(r=0) R1=ctx()
+1: (85) call bpf_loop#181
+from 1 to 3: frame1: R1=1 R10=fp0
+3: (b7) r0 = 0
+; return 0; @ prog.c:12
+4: (95) exit
+R0 !read_ok
+processed 5 insns (limit 1000000) max_states_per_insn 0 total_states 0 peak_states 0 mark_read 0
+";
+
+    #[test]
+    fn parses_every_kind_of_line() {
+        let log = VerifierLog::parse(LOG);
+        assert_eq!(log.lines[0], LogLine::Message("func#0 @0".to_string()));
+        assert_eq!(
+            log.lines[1],
+            LogLine::State {
+                insn_idx: 0,
+                state: RegState {
+                    frame: None,
+                    regs: vec![
+                        ("R1".to_string(), "ctx()".to_string()),
+                        ("R10".to_string(), "fp0".to_string()),
+                    ],
+                },
+            }
+        );
+        let LogLine::Insn {
+            insn_idx,
+            opcode,
+            text,
+            state,
+        } = &log.lines[2]
+        else {
+            panic!("not an instruction: {:?}", log.lines[2]);
+        };
+        assert_eq!((*insn_idx, *opcode), (0, 0x61));
+        assert_eq!(text, "r0 = *(u32 *)(r1 +0)");
+        assert_eq!(state.as_ref().unwrap().get("R0"), Some("pkt(r=0)"));
+        assert!(matches!(
+            &log.lines[3],
+            LogLine::Insn { insn_idx: 1, opcode: 0x85, state: None, .. }
+        ));
+        let LogLine::Branch { from, to, state } = &log.lines[4] else {
+            panic!("not a branch: {:?}", log.lines[4]);
+        };
+        assert_eq!((*from, *to, state.frame), (1, 3, Some(1)));
+        assert_eq!(
+            log.lines[6],
+            LogLine::Source("return 0; @ prog.c:12".to_string())
+        );
+    }
+
+    #[test]
+    fn keeps_parentheses_together_in_states() {
+        let state = RegState::parse("R1_w=scalar(smin=0,var_off=(0x0; 0xff)) fp-8=mmmm????")
+            .unwrap();
+        assert_eq!(state.get("R1"), Some("scalar(smin=0,var_off=(0x0; 0xff))"));
+        assert_eq!(state.get("fp-8"), Some("mmmm????"));
+        assert_eq!(state.get("R2"), None);
+        assert_eq!(RegState::parse("invalid access"), None);
+    }
+
+    #[test]
+    fn finds_the_rejected_instruction_and_reason() {
+        let log = VerifierLog::parse(LOG);
+        assert_eq!(log.last_insn(), Some(4));
+        assert_eq!(log.error().as_deref(), Some("R0 !read_ok"));
+        assert_eq!(log.states(3).count(), 1);
+        assert_eq!(log.states(0).count(), 1);
+    }
+
+    #[test]
+    fn renders_lines_as_the_kernel_prints_them() {
+        for line in LOG.lines() {
+            assert_eq!(LogLine::parse(line).to_string(), line);
+        }
+    }
+
+    #[test]
+    fn annotates_instructions_with_their_source_lines() {
+        let mut builder = BtfBuilder::new();
+        let file_name_off = builder.add_string("prog.c");
+        let line_info = [("int x = ctx->len;", 0, 10), ("return 0;", 3, 12)]
+            .map(|(source, insn_off, line)| BpfLineInfo {
+                insn_off,
+                file_name_off,
+                line_off: builder.add_string(source),
+                line_col: line << 10 | 5,
+            });
+        let raw = builder.encode().unwrap();
+        let btf = btf_parser::parse_btf(&raw, 0).unwrap();
+
+        let annotated = VerifierLog::parse(LOG).annotate(&btf, &line_info).unwrap();
+        let lines = annotated.lines().collect::<Vec<_>>();
+        assert_eq!(lines[2], "; int x = ctx->len; @ prog.c:10:5");
+        assert!(lines[3].starts_with("0: (61)"));
+        assert!(lines[4].starts_with("1: (85)"));
+        // the kernel's own source line is replaced by the one from line info
+        assert_eq!(lines[6], "; return 0; @ prog.c:12:5");
+        assert!(lines[7].starts_with("3: (b7)"));
+        assert!(lines[8].starts_with("4: (95)"));
+        assert_eq!(
+            VerifierLog::parse(LOG).annotate(&btf, &[]).unwrap(),
+            VerifierLog::parse(LOG).to_string()
+        );
+    }
+}